@@ -0,0 +1,97 @@
+use std::error::Error;
+use std::fmt;
+
+use super::*;
+
+/// How many tokens are processed between calls to `Budget::cancelled`.
+pub const CANCEL_CHECK_INTERVAL: usize = 4096;
+
+/// Limits on the work `try_prettyprint` will do before giving up.
+/// The default budget is unlimited.
+#[derive(Clone, Copy, Default)]
+pub struct Budget<'a> {
+    /// Maximum size of the source, in bytes.
+    pub max_input_size: Option<usize>,
+    /// Maximum size of the prettyprinted output, in bytes. Prettyprinting
+    /// stops at the first token after the output exceeds this size.
+    pub max_output_size: Option<usize>,
+    /// Maximum number of tokens (including comments) to process.
+    /// Tokens are counted when they are formatted; tokens scanned ahead to
+    /// decide layout (array literals, arrow parameters, case bodies) are not
+    /// counted, so the tokenizer may run somewhat past this limit.
+    pub max_tokens: Option<usize>,
+    /// Called every `CANCEL_CHECK_INTERVAL` tokens. Prettyprinting stops if
+    /// this returns true.
+    pub cancelled: Option<&'a dyn Fn() -> bool>,
+}
+
+impl<'a> Budget<'a> {
+    /// True if prettyprinting can stop early under this budget for reasons other
+    /// than the input size.
+    pub(crate) fn is_limited(&self) -> bool {
+        self.max_output_size.is_some() || self.max_tokens.is_some() || self.cancelled.is_some()
+    }
+
+    // `usize::is_multiple_of` needs Rust 1.87.
+    #[allow(clippy::manual_is_multiple_of)]
+    pub(crate) fn check(&self, tokens: usize, output_size: usize) -> Option<BudgetLimit> {
+        if self.max_tokens.map(|v| tokens >= v).unwrap_or(false) {
+            return Some(BudgetLimit::Tokens);
+        }
+        if self
+            .max_output_size
+            .map(|v| output_size > v)
+            .unwrap_or(false)
+        {
+            return Some(BudgetLimit::OutputSize);
+        }
+        if tokens % CANCEL_CHECK_INTERVAL == 0 && self.cancelled.map(|f| f()).unwrap_or(false) {
+            return Some(BudgetLimit::Cancelled);
+        }
+        None
+    }
+}
+
+/// The limit that stopped prettyprinting.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BudgetLimit {
+    InputSize,
+    OutputSize,
+    Tokens,
+    Cancelled,
+}
+
+/// Returned by `try_prettyprint` when the budget ran out.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BudgetExceeded {
+    pub limit: BudgetLimit,
+    /// Number of tokens processed before stopping.
+    pub tokens: usize,
+    /// Size in bytes of the output produced before stopping.
+    pub output_size: usize,
+    /// Position in the source of the first token that was not processed.
+    /// None if we stopped before tokenizing anything.
+    pub position: Option<SourceCoord>,
+}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match self.limit {
+            BudgetLimit::InputSize => "input too large",
+            BudgetLimit::OutputSize => "output too large",
+            BudgetLimit::Tokens => "too many tokens",
+            BudgetLimit::Cancelled => "cancelled",
+        };
+        write!(
+            f,
+            "prettyprinting stopped ({}) after {} tokens and {} bytes of output",
+            what, self.tokens, self.output_size
+        )?;
+        if let Some(p) = self.position {
+            write!(f, " at {}:{}", p.line.0 + 1, p.column.0 + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for BudgetExceeded {}
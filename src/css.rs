@@ -0,0 +1,243 @@
+/// Prettyprinting of CSS stylesheets, with the same source mappings as for JS.
+///
+/// CSS needs much less than JS: a stylesheet is a sequence of rules, at-rules
+/// and declarations ended by `{`, `}` or `;`, so we collect the tokens of each
+/// of those and lay them out on a line of their own once we see how it ends.
+use std::cmp::{max, min};
+
+use super::*;
+use crate::prettyprint::Writer;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum CssKind {
+    /// A run of anything else, e.g. `a.b`, `#fff`, `10px` or `url(x.png)`.
+    Word,
+    String,
+    Comment,
+    /// One of `{`, `}`, `;`, `:` and `,`.
+    Punct(u8),
+}
+
+struct CssToken<'a> {
+    kind: CssKind,
+    text: &'a str,
+    start: SourceCoord,
+    /// Set if whitespace separates the token from the one before.
+    space_before: bool,
+}
+
+/// Splits a stylesheet into `CssToken`s, tracking their coordinates.
+struct CssLexer<'a> {
+    source: &'a str,
+    pos: usize,
+    coord: SourceCoord,
+    last_was_cr: bool,
+}
+
+impl<'a> CssLexer<'a> {
+    fn new(source: &'a str) -> CssLexer<'a> {
+        CssLexer {
+            source,
+            pos: 0,
+            coord: SourceCoord {
+                line: SourceMapLine(0),
+                column: SourceMapColumn(0),
+            },
+            last_was_cr: false,
+        }
+    }
+    /// Move past the next `len` bytes.
+    fn advance(&mut self, len: usize) {
+        for ch in self.source[self.pos..self.pos + len].chars() {
+            match ch {
+                '\n' if self.last_was_cr => (),
+                '\n' | '\r' | '\u{c}' => {
+                    self.coord.line.0 += 1;
+                    self.coord.column.0 = 0;
+                }
+                _ => self.coord.column.0 += ch.len_utf16() as u32,
+            }
+            self.last_was_cr = ch == '\r';
+        }
+        self.pos += len;
+    }
+    /// The length of the string starting at the current position, up to and
+    /// including its closing quote.
+    fn string_len(&self) -> usize {
+        let bytes = self.source.as_bytes();
+        let quote = bytes[self.pos];
+        let mut i = self.pos + 1;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'\\' => i += 1,
+                b if b == quote => return i + 1 - self.pos,
+                // Unterminated.
+                b'\n' | b'\r' | b'\x0c' => return i - self.pos,
+                _ => (),
+            }
+            i += 1;
+        }
+        bytes.len() - self.pos
+    }
+    /// The length of the word starting at the current position.
+    fn word_len(&self) -> usize {
+        let bytes = self.source.as_bytes();
+        let mut i = self.pos;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'\\' => i += 1,
+                b'{' | b'}' | b';' | b':' | b',' | b'"' | b'\'' => break,
+                b'/' if bytes.get(i + 1) == Some(&b'*') => break,
+                b if b.is_ascii_whitespace() => break,
+                // An unquoted URL may contain anything up to its `)`.
+                b'(' if self.source[self.pos..i].eq_ignore_ascii_case("url") => {
+                    let quoted = self.source[i + 1..].trim_start().starts_with(['"', '\'']);
+                    if !quoted {
+                        return self.source[i..]
+                            .find(')')
+                            .map(|v| i + v + 1)
+                            .unwrap_or(bytes.len())
+                            - self.pos;
+                    }
+                }
+                _ => (),
+            }
+            i += 1;
+        }
+        min(i, bytes.len()) - self.pos
+    }
+}
+
+impl<'a> Iterator for CssLexer<'a> {
+    type Item = CssToken<'a>;
+    fn next(&mut self) -> Option<CssToken<'a>> {
+        let rest = &self.source[self.pos..];
+        let trimmed = rest.trim_start_matches(|c: char| c.is_ascii_whitespace());
+        let space_before = trimmed.len() < rest.len();
+        self.advance(rest.len() - trimmed.len());
+        let bytes = trimmed.as_bytes();
+        let (kind, len) = match bytes.first()? {
+            b'/' if bytes.get(1) == Some(&b'*') => (
+                CssKind::Comment,
+                trimmed[2..]
+                    .find("*/")
+                    .map(|v| v + 4)
+                    .unwrap_or(trimmed.len()),
+            ),
+            b'"' | b'\'' => (CssKind::String, self.string_len()),
+            &b if b"{};:,".contains(&b) => (CssKind::Punct(b), 1),
+            _ => (CssKind::Word, self.word_len()),
+        };
+        let token = CssToken {
+            kind,
+            text: &trimmed[..len],
+            start: self.coord,
+            space_before,
+        };
+        self.advance(len);
+        Some(token)
+    }
+}
+
+fn write_token(token: &CssToken, out: &mut Writer) {
+    out.write(token.text, token.start);
+}
+
+/// Write the tokens of a rule's selectors, an at-rule's prelude or a
+/// declaration on a line of their own at `indent_level`. `declaration` is set
+/// for a declaration, whose first `:` is followed by a space.
+fn write_statement(tokens: &[CssToken], declaration: bool, indent_level: u32, out: &mut Writer) {
+    out.write_indent(indent_level);
+    // The `:` between a declaration's property and value.
+    let mut colon = None;
+    for (i, token) in tokens.iter().enumerate() {
+        if declaration && colon.is_none() && token.kind == CssKind::Punct(b':') {
+            colon = Some(i);
+        }
+        let space = match token.kind {
+            _ if i == 0 || colon == Some(i) => false,
+            CssKind::Punct(b',') => false,
+            // `a, b` and `color: red`.
+            _ if tokens[i - 1].kind == CssKind::Punct(b',') || colon == Some(i - 1) => true,
+            // Don't glue a comment onto the value before it.
+            CssKind::Comment => token.space_before || tokens[i - 1].kind == CssKind::Word,
+            _ => token.space_before,
+        };
+        if space {
+            out.write_new(" ");
+        }
+        write_token(token, out);
+    }
+}
+
+/// Prettyprint a CSS stylesheet, putting each rule's selectors, each
+/// declaration and each at-rule on its own line, with the contents of rules and
+/// at-rules such as `@media` indented. Comments are kept, on their own line
+/// unless they are in the middle of a declaration or selector. Returns the
+/// prettyprinted text plus SourceMappings in the same form as `prettyprint`.
+///
+/// Example:
+/// ```
+/// let (pretty, _) = prettify_js::prettyprint_css("@media print{a:hover,b{color:red;margin:0 auto}}");
+/// assert_eq!(pretty, "@media print {\n  a:hover, b {\n    color: red;\n    margin: 0 auto\n  }\n}\n");
+/// ```
+pub fn prettyprint_css(source: &str) -> (String, Vec<SourceMapping>) {
+    let mut out = Writer::new(2);
+    let mut indent_level = 0;
+    let mut pending: Vec<CssToken> = Vec::new();
+    let mut tokens = CssLexer::new(source).peekable();
+    while let Some(token) = tokens.next() {
+        match token.kind {
+            CssKind::Comment if pending.is_empty() => {
+                out.write_indent(indent_level);
+                write_token(&token, &mut out);
+            }
+            CssKind::Punct(b'{') => {
+                write_statement(&pending, false, indent_level, &mut out);
+                if !pending.is_empty() {
+                    out.write_new(" ");
+                }
+                pending.clear();
+                write_token(&token, &mut out);
+                // Keep empty blocks on one line.
+                match tokens.next_if(|v| v.kind == CssKind::Punct(b'}')) {
+                    Some(close) => write_token(&close, &mut out),
+                    None => indent_level += 1,
+                }
+            }
+            CssKind::Punct(b';') => {
+                if pending.is_empty() {
+                    continue;
+                }
+                let declaration = !pending[0].text.starts_with('@');
+                write_statement(&pending, declaration, indent_level, &mut out);
+                pending.clear();
+                write_token(&token, &mut out);
+            }
+            CssKind::Punct(b'}') => {
+                if !pending.is_empty() {
+                    let declaration = !pending[0].text.starts_with('@');
+                    write_statement(&pending, declaration, indent_level, &mut out);
+                    pending.clear();
+                    out.write_new("\n");
+                }
+                indent_level = max(1, indent_level) - 1;
+                out.write_indent(indent_level);
+                write_token(&token, &mut out);
+            }
+            _ => {
+                pending.push(token);
+                continue;
+            }
+        }
+        out.write_new("\n");
+    }
+    if !pending.is_empty() {
+        let declaration = !pending[0].text.starts_with('@');
+        write_statement(&pending, declaration, indent_level, &mut out);
+        out.write_new("\n");
+    } else if out.buffer.is_empty() {
+        out.write_new("\n");
+    }
+    (out.buffer, out.mappings)
+}
@@ -0,0 +1,232 @@
+/// Prettyprinting of the inline scripts of an HTML document, leaving the rest
+/// of the document untouched.
+use super::*;
+use crate::prettyprint::{scan, Tok};
+use crate::range::{line_indent, lines_inside_tokens, Output};
+
+/// The values of a `<script>` element's `type` attribute that mean it holds
+/// JS. The attribute may also be missing or empty.
+const SCRIPT_TYPES: &[&str] = &[
+    "application/ecmascript",
+    "application/javascript",
+    "application/x-ecmascript",
+    "application/x-javascript",
+    "module",
+    "text/ecmascript",
+    "text/javascript",
+    "text/javascript1.0",
+    "text/javascript1.1",
+    "text/javascript1.2",
+    "text/javascript1.3",
+    "text/javascript1.4",
+    "text/javascript1.5",
+    "text/jscript",
+    "text/livescript",
+    "text/x-ecmascript",
+    "text/x-javascript",
+];
+
+/// An inline script found in an HTML document, as byte offsets.
+struct Script {
+    /// Just after the `>` of the `<script ...>` tag.
+    start: usize,
+    /// The start of the `</script>` tag, or the end of the document.
+    end: usize,
+}
+
+/// Returns the value of the attribute `name` in `tag`, the text of an opening
+/// tag between its name and its `>`, lowercased.
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let bytes = tag.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i].is_ascii_whitespace() || bytes[i] == b'/' {
+            i += 1;
+            continue;
+        }
+        let name_start = i;
+        while i < bytes.len() && !bytes[i].is_ascii_whitespace() && !b"/=".contains(&bytes[i]) {
+            i += 1;
+        }
+        let attribute_name = &tag[name_start..i];
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        let value = if i < bytes.len() && bytes[i] == b'=' {
+            i += 1;
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            let (value_start, value_end) = match bytes.get(i) {
+                Some(&quote) if quote == b'"' || quote == b'\'' => {
+                    let end = tag[i + 1..]
+                        .find(quote as char)
+                        .map(|v| i + 1 + v)
+                        .unwrap_or(tag.len());
+                    (i + 1, end)
+                }
+                _ => {
+                    let end = tag[i..]
+                        .find(|c: char| c.is_ascii_whitespace())
+                        .map(|v| i + v)
+                        .unwrap_or(tag.len());
+                    (i, end)
+                }
+            };
+            i = value_end + 1;
+            &tag[value_start..value_end]
+        } else {
+            ""
+        };
+        if attribute_name == name {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// True if a `<script>` element with attributes `tag` holds JS, rather than
+/// e.g. JSON, an import map or a template.
+fn is_js_script(tag: &str) -> bool {
+    match attribute(tag, "type") {
+        None => true,
+        // MIME type parameters, e.g. `text/javascript; charset=utf-8`.
+        Some(t) => {
+            let t = t.split(';').next().unwrap().trim();
+            t.is_empty() || SCRIPT_TYPES.contains(&t)
+        }
+    }
+}
+
+/// Finds the inline scripts of `source`, given `lower`, its ASCII-lowercased
+/// copy. Scripts in HTML comments are ignored.
+fn find_scripts(source: &str, lower: &str) -> Vec<Script> {
+    let mut scripts = Vec::new();
+    let mut pos = 0;
+    while let Some(i) = lower[pos..].find('<') {
+        let tag_start = pos + i;
+        let rest = &lower[tag_start..];
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            pos = comment
+                .find("-->")
+                .map(|v| tag_start + 4 + v + 3)
+                .unwrap_or(source.len());
+            continue;
+        }
+        pos = tag_start + 1;
+        let after_name = match rest.strip_prefix("<script") {
+            Some(v) => v,
+            None => continue,
+        };
+        match after_name.bytes().next() {
+            Some(b) if b.is_ascii_whitespace() || b == b'>' || b == b'/' => (),
+            _ => continue,
+        }
+        let attributes_start = tag_start + "<script".len();
+        // Attribute values may contain `>`.
+        let mut quote = None;
+        let mut tag_end = None;
+        for (j, b) in lower[attributes_start..].bytes().enumerate() {
+            match (quote, b) {
+                (Some(q), _) if b == q => quote = None,
+                (Some(_), _) => (),
+                (None, b'"') | (None, b'\'') => quote = Some(b),
+                (None, b'>') => {
+                    tag_end = Some(attributes_start + j);
+                    break;
+                }
+                _ => (),
+            }
+        }
+        let tag_end = match tag_end {
+            Some(v) => v,
+            None => break,
+        };
+        let start = tag_end + 1;
+        let end = lower[start..]
+            .find("</script")
+            .map(|v| start + v)
+            .unwrap_or(source.len());
+        if is_js_script(&lower[attributes_start..tag_end]) && !source[start..end].trim().is_empty()
+        {
+            scripts.push(Script { start, end });
+        }
+        pos = end;
+    }
+    scripts
+}
+
+/// Copy `text`, which starts at `original` in the original document, mapping
+/// the start of each of its lines to its new position.
+fn copy_text(out: &mut Output, original: &mut Output, text: &str) {
+    for line in text.split_inclusive('\n') {
+        out.mappings.push(SourceMapping {
+            from: out.coord,
+            to: original.coord,
+        });
+        out.push(line);
+        original.advance(line);
+    }
+}
+
+/// Prettyprint the inline scripts of an HTML document, i.e. the contents of its
+/// `<script>` elements without a `type` or with a JS `type`, leaving the rest of
+/// the document unchanged. Each script starts on the line after its `<script>`
+/// tag, indented one level more than the tag, and is followed by a line break.
+///
+/// Returns the full new document plus SourceMappings for the whole document,
+/// in the same form as `prettyprint`, so the original line and column of any
+/// position in the new document can be found. The text outside scripts maps
+/// line by line to its original position.
+///
+/// Example:
+/// ```
+/// use prettify_js::*;
+/// let html = "<p>Hi</p>\n<script>if(a){b()}</script>\n<script type=\"application/json\">{}</script>";
+/// let (pretty, _) = prettyprint_html(html, &PrettyprintOptions::default());
+/// assert_eq!(
+///     pretty,
+///     "<p>Hi</p>\n<script>\n  if (a) {\n    b()\n  }\n</script>\n<script type=\"application/json\">{}</script>"
+/// );
+/// ```
+pub fn prettyprint_html(
+    source: &str,
+    options: &PrettyprintOptions,
+) -> (String, Vec<SourceMapping>) {
+    let lower = source.to_ascii_lowercase();
+    let mut out = Output::new(source.len());
+    let mut original = Output::new(0);
+    let mut copied = 0;
+    for script in find_scripts(source, &lower) {
+        copy_text(&mut out, &mut original, &source[copied..script.start]);
+        let indent = format!("{}  ", line_indent(source, script.start));
+        let body = &source[script.start..script.end];
+        let (pretty, mappings) = prettyprint_with_options(body, options);
+        let tokens = scan(body, options.jsx).collect::<Vec<Tok>>();
+        let inside_tokens = lines_inside_tokens(&tokens, &mappings);
+        out.push("\n");
+        for mut m in mappings {
+            if !inside_tokens.contains(&m.from.line.0) {
+                m.from.column.0 += indent.len() as u32;
+            }
+            m.from.line.0 += out.coord.line.0;
+            if m.to.line.0 == 0 {
+                m.to.column.0 += original.coord.column.0;
+            }
+            m.to.line.0 += original.coord.line.0;
+            out.mappings.push(m);
+        }
+        for (i, line) in pretty.lines().enumerate() {
+            if !line.is_empty() && !inside_tokens.contains(&(i as u32)) {
+                out.push(&indent);
+            }
+            out.push(line);
+            out.push("\n");
+        }
+        out.push(&indent[..indent.len() - 2]);
+        original.advance(body);
+        copied = script.end;
+    }
+    copy_text(&mut out, &mut original, &source[copied..]);
+    (out.text, out.mappings)
+}
@@ -0,0 +1,114 @@
+/// Prettyprinting of JSON documents, such as configuration blobs and bundler
+/// stats, with the same source mappings as for JS.
+use super::*;
+use crate::prettyprint::{element_width, scan, scan_array, Lookahead, Tok, Writer};
+
+use ress::tokens::*;
+
+/// How the contents of an object or array are laid out.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Layout {
+    /// One property or element per line.
+    Lines,
+    /// On the line of the `{` or `[`.
+    Inline,
+    /// Elements packed into filled lines.
+    Packed,
+}
+
+/// Prettyprint a JSON document, putting each property of an object and each
+/// element of an array on its own line. Returns the prettyprinted text plus
+/// SourceMappings in the same form as `prettyprint`, ready for
+/// `generate_source_map`.
+///
+/// Arrays whose elements are all primitive values are kept on one line if
+/// their contents are at most `pack_array_width` characters wide, and
+/// otherwise packed into lines of at most that many characters, as with
+/// `PrettyprintOptions::pack_array_width`. None puts every element on its own
+/// line.
+///
+/// Example:
+/// ```
+/// let (pretty, _) = prettify_js::prettyprint_json("{\"a\":[1,2],\"b\":{}}", Some(20));
+/// assert_eq!(pretty, "{\n  \"a\": [1, 2],\n  \"b\": {}\n}\n");
+/// ```
+pub fn prettyprint_json(
+    source: &str,
+    pack_array_width: Option<usize>,
+) -> (String, Vec<SourceMapping>) {
+    let mut out = Writer::new(2);
+    let mut tokens = Lookahead::new(scan(source, false));
+    let mut stack: Vec<Layout> = Vec::new();
+    let mut last_token: Option<Tok> = None;
+    while let Some(token) = tokens.next() {
+        if let &Token::EoF = &token.token {
+            break;
+        }
+        let layout = stack.last().cloned();
+        let last = last_token.as_ref().map(|v| &v.token);
+        match &token.token {
+            &Token::Punct(Punct::CloseBrace) | &Token::Punct(Punct::CloseBracket) => {
+                stack.pop();
+                if layout == Some(Layout::Lines) || layout == Some(Layout::Packed) {
+                    out.write_new("\n");
+                    out.write_indent(stack.len() as u32);
+                }
+            }
+            _ => match last {
+                Some(&Token::Punct(Punct::OpenBrace)) | Some(&Token::Punct(Punct::OpenBracket))
+                    if layout != Some(Layout::Inline) =>
+                {
+                    out.write_new("\n");
+                    out.write_indent(stack.len() as u32);
+                }
+                Some(&Token::Punct(Punct::Comma)) if layout == Some(Layout::Packed) => {
+                    let used = out.current.column.0 - stack.len() as u32 * out.indent;
+                    let width = element_width(&token, &mut tokens, false);
+                    if used as usize + 1 + width > pack_array_width.unwrap_or(0) {
+                        out.write_new("\n");
+                        out.write_indent(stack.len() as u32);
+                    } else {
+                        out.write_new(" ");
+                    }
+                }
+                Some(&Token::Punct(Punct::Comma)) if layout != Some(Layout::Inline) => {
+                    out.write_new("\n");
+                    out.write_indent(stack.len() as u32);
+                }
+                Some(&Token::Punct(Punct::Comma)) | Some(&Token::Punct(Punct::Colon)) => {
+                    out.write_new(" ");
+                }
+                // A sequence of documents, e.g. JSON Lines.
+                Some(&Token::Punct(Punct::Dash)) | Some(&Token::Punct(Punct::Plus)) | None => (),
+                Some(_) if stack.is_empty() => out.write_new("\n"),
+                Some(_) => (),
+            },
+        }
+        out.write(&token.text(), token.start);
+        match &token.token {
+            &Token::Punct(Punct::OpenBrace) | &Token::Punct(Punct::OpenBracket) => {
+                let empty = matches!(
+                    tokens.peek().map(|v| &v.token),
+                    Some(&Token::Punct(Punct::CloseBrace))
+                        | Some(&Token::Punct(Punct::CloseBracket))
+                );
+                let layout = match pack_array_width {
+                    _ if empty => Layout::Inline,
+                    Some(max_width) if token.token == Token::Punct(Punct::OpenBracket) => {
+                        match scan_array(&mut tokens, false) {
+                            Some(width) if width <= max_width => Layout::Inline,
+                            Some(_) => Layout::Packed,
+                            None => Layout::Lines,
+                        }
+                    }
+                    _ => Layout::Lines,
+                };
+                stack.push(layout);
+            }
+            _ => (),
+        }
+        last_token = Some(token);
+    }
+    out.write_new("\n");
+    (out.buffer, out.mappings)
+}
@@ -0,0 +1,423 @@
+/// JSX elements in JS: how the `Lexer` scans them in JSX mode, which RESS knows
+/// nothing about, and how the `Formatter` lays them out.
+use std::cmp::max;
+
+use super::*;
+use crate::prettyprint::*;
+
+use ress::tokens::*;
+use ress::*;
+
+/// Which part of a JSX element a token is, as recognized by a `Lexer` in JSX
+/// mode. Names and lines of text are `Ident`s, and attribute values are
+/// `String`s.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Jsx {
+    /// The `<` that starts an opening tag, e.g. `<div` or `<>`.
+    OpenTag,
+    /// The `<` that starts a closing tag, e.g. `</div>`.
+    CloseTag,
+    /// The `/` of a closing tag, or of the `/>` that ends a self-closing tag.
+    Slash,
+    /// An element's name, in its opening or closing tag.
+    Name,
+    /// An attribute's name.
+    Attribute,
+    /// The `=` between an attribute's name and value.
+    Equals,
+    /// An attribute's string value.
+    Value,
+    /// The `{` that starts an attribute's expression value, or a spread
+    /// attribute.
+    ExpressionStart,
+    /// The `}` that ends an attribute's expression.
+    ExpressionEnd,
+    /// The `>` that ends an opening tag, before the element's children.
+    TagEnd,
+    /// The `>` that ends an element: the end of its closing tag, or of a
+    /// self-closing tag.
+    ElementEnd,
+    /// One line of the text among an element's children.
+    Text,
+    /// The `{` that starts an expression among an element's children.
+    ChildStart,
+    /// The `}` that ends an expression among an element's children.
+    ChildEnd,
+}
+
+/// True if a `<` after `token` is a less-than operator rather than the start of
+/// a JSX element.
+pub(crate) fn precedes_less_than(token: &Token<&str>) -> bool {
+    matches!(
+        token,
+        &Token::Boolean(_)
+            | &Token::Ident(_)
+            | &Token::Null
+            | &Token::Number(_)
+            | &Token::RegEx(_)
+            | &Token::String(_)
+            | &Token::Template(_)
+            | &Token::Keyword(Keyword::Super(_))
+            | &Token::Keyword(Keyword::This(_))
+            | &Token::Punct(Punct::CloseBrace)
+            | &Token::Punct(Punct::CloseBracket)
+            | &Token::Punct(Punct::CloseParen)
+    )
+}
+
+fn is_jsx_name_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric()
+        || b == b'_'
+        || b == b'$'
+        || b == b'-'
+        || b == b':'
+        || b == b'.'
+        || b >= 0x80
+}
+
+/// Where a `Lexer` in JSX mode is within the JSX elements it is scanning.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum JsxContext {
+    /// Among the name and attributes of a tag, which is a closing tag if
+    /// `closing`.
+    Tag { closing: bool },
+    /// Among an element's children.
+    Children,
+    /// In an expression in braces, nested in this many more braces.
+    Expression(usize),
+}
+
+impl<'a> Lexer<'a> {
+    /// True if the next token is a `<` that starts a JSX element.
+    pub(crate) fn starts_jsx(&self) -> bool {
+        if !self.jsx || self.less_than {
+            return false;
+        }
+        match self.jsx_stack.last() {
+            None | Some(&JsxContext::Expression(_)) => (),
+            Some(_) => return false,
+        }
+        let rest = &self.source.as_bytes()[self.cursor().idx..];
+        rest.first() == Some(&b'<')
+            && rest
+                .get(1)
+                .map(|&b| b == b'>' || (is_jsx_name_byte(b) && !b.is_ascii_digit() && b != b'-'))
+                .unwrap_or(false)
+    }
+
+    /// Scan the next part of the JSX element we are in. Returns None if the
+    /// element is malformed.
+    pub(crate) fn next_jsx(&mut self) -> Option<Result<Item<&'a str>, ress::error::Error>> {
+        let context = *self.jsx_stack.last()?;
+        let bytes = self.source.as_bytes();
+        let start = self.cursor();
+        let mut cursor = start;
+        let b = *bytes.get(cursor.idx)?;
+        let (token, role) = match (context, b) {
+            (JsxContext::Expression(_), _) => return None,
+            (JsxContext::Children, b'{') | (JsxContext::Tag { .. }, b'{') => {
+                // RESS scans the braces, so that it keeps track of them.
+                let item = self.scanner.next()?;
+                self.jsx_role = Some(if context == JsxContext::Children {
+                    Jsx::ChildStart
+                } else {
+                    Jsx::ExpressionStart
+                });
+                self.jsx_stack.push(JsxContext::Expression(0));
+                self.less_than = false;
+                self.after_period = false;
+                self.operand_line = None;
+                self.postfix_end = None;
+                return Some(item);
+            }
+            (JsxContext::Children, b'<') => {
+                let closing = bytes.get(cursor.idx + 1) == Some(&b'/');
+                self.jsx_stack.push(JsxContext::Tag { closing });
+                self.advance(&mut cursor, 1);
+                let role = if closing { Jsx::CloseTag } else { Jsx::OpenTag };
+                (Token::Punct(Punct::LessThan), role)
+            }
+            (JsxContext::Children, _) => {
+                let rest = &self.source[cursor.idx..];
+                let len = rest.find(['<', '{', '\n', '\r']).unwrap_or(rest.len());
+                let text = rest[..len].trim_end();
+                self.advance(&mut cursor, text.len());
+                (Token::Ident(Ident::from(text)), Jsx::Text)
+            }
+            (JsxContext::Tag { .. }, b'/') => {
+                self.advance(&mut cursor, 1);
+                (Token::Punct(Punct::ForwardSlash), Jsx::Slash)
+            }
+            (JsxContext::Tag { closing }, b'>') => {
+                self.advance(&mut cursor, 1);
+                self.jsx_stack.pop();
+                let role = if closing {
+                    // The closing tag ends the element's children too.
+                    self.jsx_stack.pop();
+                    Jsx::ElementEnd
+                } else if self.jsx_role == Some(Jsx::Slash) {
+                    Jsx::ElementEnd
+                } else {
+                    self.jsx_stack.push(JsxContext::Children);
+                    Jsx::TagEnd
+                };
+                (Token::Punct(Punct::GreaterThan), role)
+            }
+            (JsxContext::Tag { .. }, b'=') => {
+                self.advance(&mut cursor, 1);
+                (Token::Punct(Punct::Equal), Jsx::Equals)
+            }
+            (JsxContext::Tag { .. }, b'"') | (JsxContext::Tag { .. }, b'\'') => {
+                // JSX strings have no escapes.
+                let len = self.source[cursor.idx + 1..].find(b as char)? + 2;
+                let inner = InnerString {
+                    content: &self.source[cursor.idx + 1..cursor.idx + len - 1],
+                    contains_octal_escape: false,
+                };
+                self.advance(&mut cursor, len);
+                let string = if b == b'"' {
+                    StringLit::Double(inner)
+                } else {
+                    StringLit::Single(inner)
+                };
+                (Token::String(string), Jsx::Value)
+            }
+            (JsxContext::Tag { .. }, _) if is_jsx_name_byte(b) => {
+                let len = bytes[cursor.idx..]
+                    .iter()
+                    .position(|&b| !is_jsx_name_byte(b))
+                    .unwrap_or(bytes.len() - cursor.idx);
+                let name = &self.source[cursor.idx..cursor.idx + len];
+                self.advance(&mut cursor, len);
+                let role = match self.jsx_role {
+                    Some(Jsx::OpenTag) | Some(Jsx::Slash) => Jsx::Name,
+                    _ => Jsx::Attribute,
+                };
+                (Token::Ident(Ident::from(name)), role)
+            }
+            (JsxContext::Tag { .. }, _) => return None,
+        };
+        let item = Item::new(
+            token,
+            Span::new(start.idx, cursor.idx),
+            SourceLocation::new(
+                Position::new(start.line + 1, start.column),
+                Position::new(cursor.line + 1, cursor.column),
+            ),
+        );
+        self.jsx_role = Some(role);
+        self.resume(cursor);
+        if role == Jsx::ElementEnd && !self.in_jsx_element() {
+            // The element is an operand, so RESS must take a `/` after it for a
+            // division.
+            let mut state = self.scanner.get_state();
+            state
+                .last_three
+                .push((&Token::Ident(Ident::from("")), 0).into());
+            self.scanner.set_state(state);
+            self.less_than = true;
+            self.after_period = false;
+            self.operand_line = None;
+            self.postfix_end = None;
+        }
+        Some(Ok(item))
+    }
+
+    /// True if the next token is part of a JSX element, rather than JS.
+    pub(crate) fn in_jsx_element(&self) -> bool {
+        matches!(
+            self.jsx_stack.last(),
+            Some(&JsxContext::Tag { .. }) | Some(&JsxContext::Children)
+        )
+    }
+
+    /// Scan the `<` that starts a JSX element, once `starts_jsx` says so.
+    pub(crate) fn open_jsx_element(&mut self) -> Item<&'a str> {
+        self.jsx_stack.push(JsxContext::Tag { closing: false });
+        let start = self.cursor();
+        let mut cursor = start;
+        self.advance(&mut cursor, 1);
+        self.jsx_role = Some(Jsx::OpenTag);
+        self.resume(cursor);
+        Item::new(
+            Token::Punct(Punct::LessThan),
+            Span::new(start.idx, cursor.idx),
+            SourceLocation::new(
+                Position::new(start.line + 1, start.column),
+                Position::new(cursor.line + 1, cursor.column),
+            ),
+        )
+    }
+
+    /// Keep track of the braces of a JSX expression that RESS scanned `token`
+    /// in, noting when `token` ends the expression.
+    pub(crate) fn track_jsx_expression(&mut self, token: &Token<&str>) {
+        if let Some(&JsxContext::Expression(depth)) = self.jsx_stack.last() {
+            match token {
+                Token::Punct(Punct::OpenBrace) => {
+                    self.jsx_stack.pop();
+                    self.jsx_stack.push(JsxContext::Expression(depth + 1));
+                }
+                Token::Punct(Punct::CloseBrace) if depth == 0 => {
+                    self.jsx_stack.pop();
+                    self.jsx_role = Some(if self.jsx_stack.last() == Some(&JsxContext::Children) {
+                        Jsx::ChildEnd
+                    } else {
+                        Jsx::ExpressionEnd
+                    });
+                }
+                Token::Punct(Punct::CloseBrace) => {
+                    self.jsx_stack.pop();
+                    self.jsx_stack.push(JsxContext::Expression(depth - 1));
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Look ahead from just after the `>` of a JSX element's opening tag, which
+/// ends at `start`. Returns whether any of its children is an element or
+/// contains one, so that the children can go on lines of their own. They can't
+/// if whitespace on a single line separates two of them, e.g. `Hi <b>there</b>`,
+/// since a line break in its place would remove the space.
+fn has_child_elements<'a, I>(tokens: &mut Lookahead<I>, start: SourceCoord) -> bool
+where
+    I: Iterator<Item = Tok<'a>>,
+{
+    let mut elements = false;
+    // How deep in the children's elements and expressions we are.
+    let mut depth = 0;
+    // The end of the last child, or the `>`.
+    let mut last_end = Some(start);
+    for i in 0..BRACE_LOOKAHEAD {
+        let t = match tokens.peek_nth(i) {
+            Some(t) => t,
+            None => return false,
+        };
+        let jsx = match t.jsx {
+            Some(v) => v,
+            None => continue,
+        };
+        if depth == 0 {
+            if let Some(end) = last_end.take() {
+                if end.line == t.start.line && end != t.start {
+                    return false;
+                }
+            }
+        }
+        match jsx {
+            Jsx::OpenTag => {
+                elements = true;
+                depth += 1;
+            }
+            Jsx::ChildStart => depth += 1,
+            Jsx::CloseTag if depth == 0 => return elements,
+            Jsx::ElementEnd | Jsx::ChildEnd => depth -= 1,
+            _ => (),
+        }
+        if depth == 0 {
+            last_end = Some(t.end);
+        }
+    }
+    elements
+}
+
+impl<'a> Formatter<'a> {
+    pub(crate) fn format_jsx<I>(&mut self, mut token: Tok<'a>, jsx: Jsx, tokens: &mut Lookahead<I>)
+    where
+        I: Iterator<Item = Tok<'a>>,
+    {
+        let last_jsx = self.last_token.as_ref().and_then(|v| v.jsx);
+        let child = match jsx {
+            Jsx::OpenTag | Jsx::CloseTag | Jsx::Text | Jsx::ChildStart => matches!(
+                last_jsx,
+                Some(Jsx::TagEnd) | Some(Jsx::ElementEnd) | Some(Jsx::Text) | Some(Jsx::ChildEnd)
+            ),
+            _ => false,
+        };
+        if child && self.jsx_elements.last() == Some(&true) {
+            if jsx == Jsx::CloseTag {
+                self.indent_level = max(1, self.indent_level) - 1;
+            }
+            if !self.added_newline {
+                self.out.write_new("\n");
+            }
+            self.out.write_indent(self.indent_level);
+        } else if self.added_newline {
+            self.out.write_indent(self.indent_level);
+        } else if child {
+            // Whitespace between children is significant, unless it spans
+            // lines: then it separates lines of text, or nothing.
+            let gap = self
+                .last_token
+                .as_ref()
+                .map(|v| {
+                    v.end != token.start
+                        && (v.end.line == token.start.line
+                            || (last_jsx == Some(Jsx::Text) && jsx == Jsx::Text))
+                })
+                .unwrap_or(false);
+            if gap {
+                self.out.write_new(" ");
+            }
+        } else if jsx == Jsx::OpenTag {
+            // An element is an operand in the surrounding JS.
+            let mut operand = token.clone();
+            operand.token = Token::Ident(Ident::from(""));
+            if is_asi(&operand, &self.last_token) {
+                self.out.write_new("\n");
+                self.out.write_indent(self.indent_level);
+            } else if !self.added_space && need_space_after(&operand, &self.last_token) {
+                self.out.write_new(" ");
+            }
+        } else {
+            let space = match jsx {
+                Jsx::Attribute => true,
+                Jsx::ExpressionStart => last_jsx != Some(Jsx::Equals),
+                Jsx::Slash => last_jsx != Some(Jsx::CloseTag),
+                _ => false,
+            };
+            if space {
+                self.out.write_new(" ");
+            }
+        }
+
+        match jsx {
+            Jsx::OpenTag => self.jsx_elements.push(false),
+            Jsx::TagEnd => {
+                let multiline = !is_inline(&self.stack) && has_child_elements(tokens, token.end);
+                if let Some(v) = self.jsx_elements.last_mut() {
+                    *v = multiline;
+                }
+                if multiline {
+                    self.indent_level += 1;
+                }
+            }
+            Jsx::ElementEnd => {
+                self.jsx_elements.pop();
+            }
+            Jsx::ExpressionStart | Jsx::ChildStart => {
+                token.brace_kind = BraceKind::ObjectLiteral;
+                token.inline = true;
+                self.stack.push(token.clone());
+            }
+            Jsx::ExpressionEnd | Jsx::ChildEnd => {
+                self.stack.pop();
+            }
+            _ => (),
+        }
+
+        self.out.write(&token.text(), token.start);
+        self.last_token_end = self.out.buffer.len();
+        self.added_newline = false;
+        self.added_space = false;
+        self.last_line = Some(token.end.line.0);
+        if jsx == Jsx::ElementEnd {
+            // What follows treats the element as an operand.
+            token.token = Token::Ident(Ident::from(""));
+            token.replaced = Some(">".to_string());
+        }
+        self.last_token = Some(token);
+    }
+}
@@ -6,10 +6,22 @@
 //! assert_eq!(pretty, "function x(a) {\n  return a;\n}\n");
 //! ```
 
+mod budget;
+mod css;
+mod html;
+mod json;
+mod jsx;
+mod parallel;
 mod prettyprint;
+mod range;
 mod source_map_generator;
 
+pub use budget::*;
+pub use css::*;
+pub use html::*;
+pub use json::*;
 pub use prettyprint::*;
+pub use range::*;
 pub use source_map_generator::*;
 
 /// Uses a heuristic to decide if the source file needs prettyprinting:
@@ -52,19 +64,61 @@ pub fn should_prettyprint(source_str: &str) -> bool {
 ///   });
 /// assert_eq!(generated, "//PRETTYPRINT\nfunction x(a){return a;}\n//# sourceMappingURL=https://example.com/demo.js.sourcemap");
 /// ```
-pub fn maybe_prettyprint<G>(script_name: &str, source_str: &mut String, mut generate_file: G)
+pub fn maybe_prettyprint<G>(script_name: &str, source_str: &mut String, generate_file: G)
 where
     G: FnMut(String, String) -> String,
 {
     if !should_prettyprint(source_str) {
         return;
     }
-    let (pretty_str, mappings) = prettyprint(&source_str);
-    let source_map_name = format!("{}.sourcemap", script_name);
-    let pretty_name = format!("{}.pretty", script_name);
-    // The source map maps *from* prettyprinted source *to* the obfuscated/minified source
-    let source_map = generate_source_map(pretty_name, pretty_str, mappings);
-    let url = generate_file(source_map_name, source_map);
+    let (pretty_str, mappings) = prettyprint(source_str);
+    let url = generate_source_map_file(script_name, pretty_str, mappings, generate_file);
     source_str.push_str("\n//# sourceMappingURL=");
     source_str.push_str(&url);
 }
+
+/// Like `maybe_prettyprint`, but for CSS stylesheets, using `prettyprint_css`.
+/// The URL is appended in a `/*# sourceMappingURL=... */` comment, since CSS
+/// has no `//` comments.
+///
+/// Example:
+/// ```
+/// let mut generated = format!("a{{color:red}}{}", "b{color:blue}".repeat(10));
+/// prettify_js::maybe_prettyprint_css("demo.css", &mut generated,
+///   |name, _| {
+///     assert_eq!(name, "demo.css.sourcemap");
+///     "https://example.com/demo.css.sourcemap".to_string()
+///   });
+/// assert!(generated.ends_with("}\n/*# sourceMappingURL=https://example.com/demo.css.sourcemap */"));
+/// ```
+pub fn maybe_prettyprint_css<G>(stylesheet_name: &str, source_str: &mut String, generate_file: G)
+where
+    G: FnMut(String, String) -> String,
+{
+    if !should_prettyprint(source_str) {
+        return;
+    }
+    let (pretty_str, mappings) = prettyprint_css(source_str);
+    let url = generate_source_map_file(stylesheet_name, pretty_str, mappings, generate_file);
+    source_str.push_str("\n/*# sourceMappingURL=");
+    source_str.push_str(&url);
+    source_str.push_str(" */");
+}
+
+/// Generate the source map for the prettyprinted version of the file `name` and
+/// pass it to `generate_file`. Returns the source map's URL.
+fn generate_source_map_file<G>(
+    name: &str,
+    pretty_str: String,
+    mappings: Vec<SourceMapping>,
+    mut generate_file: G,
+) -> String
+where
+    G: FnMut(String, String) -> String,
+{
+    let source_map_name = format!("{}.sourcemap", name);
+    let pretty_name = format!("{}.pretty", name);
+    // The source map maps *from* prettyprinted source *to* the obfuscated/minified source
+    let source_map = generate_source_map(pretty_name, pretty_str, mappings);
+    generate_file(source_map_name, source_map)
+}
@@ -0,0 +1,362 @@
+/// Parallel prettyprinting of large sources.
+///
+/// A quick lexical pre-scan finds `;` tokens at bracket depth zero, roughly evenly spaced
+/// through the source. The source is split just after those tokens, each piece is
+/// tokenized and prettyprinted on its own thread, and the results are stitched back
+/// together. The pre-scan does not need to be exact: each piece is checked after it has
+/// been prettyprinted, and if any piece did not end cleanly at a top-level statement
+/// boundary we give up and let the caller prettyprint sequentially.
+use std::cmp::{max, min};
+use std::thread;
+
+use super::*;
+use crate::prettyprint::{convert_token, Formatter, Lexer};
+
+use ress::tokens::*;
+
+/// Keywords after which a `/` starts a regular expression rather than a division.
+const PRE_REGEX_KEYWORDS: &[&[u8]] = &[
+    b"await",
+    b"case",
+    b"delete",
+    b"do",
+    b"else",
+    b"in",
+    b"instanceof",
+    b"new",
+    b"of",
+    b"return",
+    b"throw",
+    b"typeof",
+    b"void",
+    b"yield",
+];
+
+fn is_ident_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b == b'$' || b >= 0x80
+}
+
+fn skip_whitespace(bytes: &[u8], mut i: usize) -> usize {
+    while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+        i += 1;
+    }
+    i
+}
+
+/// True if the statement starting at `i` can begin a new piece.
+fn can_start_piece(bytes: &[u8], i: usize) -> bool {
+    let rest = &bytes[i..];
+    if rest.is_empty() || rest.starts_with(b"//") || rest.starts_with(b"/*") {
+        return false;
+    }
+    // `else` is spaced according to the token before it, so must not start a piece.
+    !rest.starts_with(b"else") || rest.get(4).map(|b| is_ident_byte(*b)).unwrap_or(false)
+}
+
+/// Returns the index just after the end of the template literal (or `${`) whose
+/// body starts at `i`, and whether we stopped at a `${`.
+fn skip_template(bytes: &[u8], mut i: usize) -> (usize, bool) {
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'`' => return (i + 1, false),
+            b'$' if bytes.get(i + 1) == Some(&b'{') => return (i + 2, true),
+            _ => i += 1,
+        }
+    }
+    (i, false)
+}
+
+/// Find up to `pieces - 1` byte offsets, each at the start of the first token after a
+/// top-level `;`, that split `source` into pieces of roughly equal size.
+/// Pieces never start with whitespace because RESS miscounts columns on the first
+/// line when the input starts with whitespace.
+fn find_split_points(source: &str, pieces: usize) -> Vec<usize> {
+    let bytes = source.as_bytes();
+    let mut splits = Vec::new();
+    let mut next_target = bytes.len() / pieces;
+    let mut depth = 0usize;
+    // Bracket depths at which `${` substitutions were opened in template literals.
+    let mut templates: Vec<usize> = Vec::new();
+    // Whether a `/` at the current position would start a regular expression.
+    let mut regex_allowed = true;
+    let mut i = 0;
+    while i < bytes.len() && splits.len() + 1 < pieces {
+        let b = bytes[i];
+        match b {
+            b'"' | b'\'' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != b {
+                    if bytes[i] == b'\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                i += 1;
+                regex_allowed = false;
+                continue;
+            }
+            b'`' => {
+                let (end, substitution) = skip_template(bytes, i + 1);
+                if substitution {
+                    templates.push(depth);
+                    depth += 1;
+                    regex_allowed = true;
+                } else {
+                    regex_allowed = false;
+                }
+                i = end;
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i += 2;
+                while i < bytes.len() && !bytes[i..].starts_with(b"*/") {
+                    i += 1;
+                }
+                i += 2;
+                continue;
+            }
+            b'/' if regex_allowed => {
+                let mut in_class = false;
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    match bytes[i] {
+                        b'\\' => i += 1,
+                        b'[' => in_class = true,
+                        b']' => in_class = false,
+                        b'/' if !in_class => break,
+                        _ => (),
+                    }
+                    i += 1;
+                }
+                i += 1;
+                regex_allowed = false;
+                continue;
+            }
+            b'(' | b'[' | b'{' => {
+                depth += 1;
+                regex_allowed = true;
+            }
+            b')' | b']' => {
+                depth = depth.saturating_sub(1);
+                regex_allowed = false;
+            }
+            b'}' => {
+                depth = depth.saturating_sub(1);
+                if templates.last() == Some(&depth) {
+                    templates.pop();
+                    let (end, substitution) = skip_template(bytes, i + 1);
+                    if substitution {
+                        templates.push(depth);
+                        depth += 1;
+                        regex_allowed = true;
+                    } else {
+                        regex_allowed = false;
+                    }
+                    i = end;
+                    continue;
+                }
+                regex_allowed = false;
+            }
+            b';' => {
+                regex_allowed = true;
+                if depth == 0 && templates.is_empty() && i + 1 >= next_target {
+                    let start = skip_whitespace(bytes, i + 1);
+                    if can_start_piece(bytes, start) {
+                        splits.push(start);
+                        next_target = start + (bytes.len() - start) / (pieces - splits.len());
+                    }
+                }
+            }
+            _ if is_ident_byte(b) => {
+                let start = i;
+                while i < bytes.len() && is_ident_byte(bytes[i]) {
+                    i += 1;
+                }
+                regex_allowed = PRE_REGEX_KEYWORDS.contains(&&bytes[start..i]);
+                continue;
+            }
+            _ if b.is_ascii_whitespace() => (),
+            _ => regex_allowed = true,
+        }
+        i += 1;
+    }
+    splits
+}
+
+/// A prettyprinted piece of the source. Coordinates are relative to the start of the piece.
+struct Piece {
+    text: String,
+    mappings: Vec<SourceMapping>,
+    /// Number of lines in `text`.
+    lines: u32,
+    /// End of the piece text.
+    end: SourceCoord,
+    /// How many blank lines precede the statement after the piece.
+    blank_lines: usize,
+}
+
+/// Prettyprint one piece, returning None if the result cannot be stitched together
+/// with its neighbours so as to match what sequential prettyprinting would produce.
+fn prettyprint_piece(
+    text: &str,
+    options: &PrettyprintOptions,
+    first: bool,
+    last: bool,
+) -> Option<Piece> {
+    let mut errored = false;
+    let mut formatter = Formatter::new(options);
+    formatter.continuation = !first;
+    {
+        let mut tokens = Lexer::new(text)
+            .filter_map(|v| match v {
+                Ok(v) => Some(convert_token(v)),
+                Err(_) => {
+                    errored = true;
+                    None
+                }
+            })
+            .peekable();
+        if !first {
+            match tokens.peek().map(|v| &v.token) {
+                Some(&Token::Comment(_))
+                | Some(&Token::Keyword(Keyword::Else(_)))
+                | Some(&Token::Punct(Punct::SemiColon))
+                | None => {
+                    return None;
+                }
+                _ => (),
+            }
+        }
+        formatter.format(&mut tokens);
+    }
+    if errored {
+        return None;
+    }
+    let mut end = match formatter.last_token.as_ref() {
+        Some(t) if last || t.token == Token::Punct(Punct::SemiColon) => t.end,
+        _ => return None,
+    };
+    // Only ASCII whitespace follows the last token, so counting bytes here
+    // matches RESS's byte-based columns. RESS counts the `\r` and `\n` of a
+    // `\r\n` as a line break each, and so must we.
+    let trailing = &text[text
+        .trim_end_matches(|c: char| c.is_ascii_whitespace())
+        .len()..];
+    let mut newlines = 0;
+    for c in trailing.chars() {
+        if c == '\n' || c == '\r' {
+            newlines += 1;
+            end.line.0 += 1;
+            end.column.0 = 0;
+        } else {
+            end.column.0 += 1;
+        }
+    }
+    if !last && !formatter.at_top_level() {
+        return None;
+    }
+    let lines = formatter.out.current.line.0;
+    let blank_lines = max(
+        (formatter.blank_line == Some(0)) as usize,
+        min(options.preserve_blank_lines, max(1, newlines) - 1),
+    );
+    let (text, mappings) = formatter.finish();
+    Some(Piece {
+        text,
+        mappings,
+        lines,
+        end,
+        blank_lines,
+    })
+}
+
+/// Prettyprint `source` using up to `options.threads` threads. Returns None if the
+/// source is too small to be worth splitting or could not be split safely.
+pub(crate) fn prettyprint_parallel(
+    source: &str,
+    options: &PrettyprintOptions,
+) -> Option<(String, Vec<SourceMapping>)> {
+    // The pre-scan would take JSX text for code.
+    if options.jsx {
+        return None;
+    }
+    let pieces = options
+        .threads
+        .min(source.len() / max(1, options.min_chunk_size));
+    if pieces < 2 {
+        return None;
+    }
+    let splits = find_split_points(source, pieces);
+    if splits.is_empty() {
+        return None;
+    }
+    let mut ranges = Vec::with_capacity(splits.len() + 1);
+    let mut start = 0;
+    for split in splits {
+        ranges.push(start..split);
+        start = split;
+    }
+    ranges.push(start..source.len());
+
+    let count = ranges.len();
+    let pieces = thread::scope(|s| {
+        let handles = ranges
+            .into_iter()
+            .enumerate()
+            .map(|(i, range)| {
+                s.spawn(move || prettyprint_piece(&source[range], options, i == 0, i + 1 == count))
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Option<Vec<_>>>()
+    })?;
+
+    let mut buffer = String::with_capacity(pieces.iter().map(|p| p.text.len()).sum());
+    let mut mappings = Vec::with_capacity(pieces.iter().map(|p| p.mappings.len()).sum());
+    let mut pretty_line = 0;
+    let mut origin = SourceCoord {
+        line: SourceMapLine(0),
+        column: SourceMapColumn(0),
+    };
+    let mut blank_lines = 0;
+    for piece in pieces {
+        // A piece can start with some of its blank lines already.
+        let leading = piece.text.bytes().take_while(|&b| b == b'\n').count();
+        for _ in leading..blank_lines {
+            buffer.push('\n');
+            pretty_line += 1;
+        }
+        blank_lines = piece.blank_lines;
+        for mut m in piece.mappings {
+            m.from.line.0 += pretty_line;
+            if m.to.line.0 == 0 {
+                m.to.column.0 += origin.column.0;
+            }
+            m.to.line.0 += origin.line.0;
+            mappings.push(m);
+        }
+        buffer.push_str(&piece.text);
+        pretty_line += piece.lines;
+        origin = if piece.end.line.0 == 0 {
+            SourceCoord {
+                line: origin.line,
+                column: SourceMapColumn(origin.column.0 + piece.end.column.0),
+            }
+        } else {
+            SourceCoord {
+                line: SourceMapLine(origin.line.0 + piece.end.line.0),
+                column: piece.end.column,
+            }
+        };
+    }
+    Some((buffer, mappings))
+}
@@ -5,20 +5,120 @@
 /// pretty-fast uses the Acorn tokenizer, which tokenizes into template strings. The RESS tokenizer
 /// returns the entire template string as a token, so template-string handling is a little different.
 /// In particular there are no ${ tokens.
-use std::cmp::max;
+use std::cmp::{max, min};
+use std::collections::VecDeque;
 
 use super::*;
+use crate::jsx::*;
 
 use log::debug;
 use ress::tokens::*;
 use ress::*;
 
+/// What an `OpenBrace` on the stack opens.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum BraceKind {
+    Block,
+    ObjectLiteral,
+    /// A destructuring pattern, e.g. `{a, b} = x`.
+    Pattern,
+    ClassBody,
+}
+
+/// Which part of a control statement a head or body is. This determines what
+/// may follow the body.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Clause {
+    /// `if (...)`, which may be followed by `else`.
+    If,
+    /// `try`, which is followed by `catch` or `finally`.
+    Try,
+    /// `catch (...)`, which may be followed by `finally`.
+    Catch,
+    /// `do`, which is followed by `while (...)`.
+    Do,
+    /// The `while (...)` after the body of a `do`, which ends the statement,
+    /// optionally with a `;`.
+    DoWhile,
+    /// Anything else, which ends the statement.
+    Last,
+}
+
+/// What a token is in TypeScript syntax, in TypeScript mode.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum TypeScript {
+    /// The `<` that starts type parameters or arguments, e.g. `Map<K, V>`.
+    OpenAngle,
+    /// The `>` that ends them.
+    CloseAngle,
+    /// The `?` that marks an optional property or parameter, e.g. `a?: T`.
+    Optional,
+    /// The `:` before a type annotation, e.g. `let a: number` or `f(): T`.
+    TypeColon,
+}
+
+/// How many tokens we look ahead of an object literal's `{` for its `}`.
+pub(crate) const BRACE_LOOKAHEAD: usize = 256;
+/// How many tokens we look ahead of an array literal's `[` for its `]`. Lookup
+/// tables can be long, but we stop at the first element that is not a primitive.
+const ARRAY_LOOKAHEAD: usize = 1 << 16;
+
 #[derive(Clone)]
-struct Tok<'a> {
-    token: Token<&'a str>,
-    start: SourceCoord,
-    end: SourceCoord,
+pub(crate) struct Tok<'a> {
+    pub(crate) token: Token<&'a str>,
+    pub(crate) start: SourceCoord,
+    pub(crate) end: SourceCoord,
     starts_array_literal: bool,
+    /// The original text, when `token` stands in for something that formats
+    /// the same way: operators joined from several RESS tokens (see
+    /// `JoinOperators`), or keywords used as property names.
+    pub(crate) replaced: Option<String>,
+    /// Only meaningful for `OpenBrace` and `CloseBrace`.
+    pub(crate) brace_kind: BraceKind,
+    /// Set on stack entries inside an object literal or pattern that is
+    /// being kept on one line.
+    pub(crate) inline: bool,
+    /// Set on the `:` of a conditional expression.
+    ends_ternary_test: bool,
+    /// Set on the `:` that ends a `case` or `default` label.
+    ends_case: bool,
+    /// Set on the first token of a statement, as far as we can tell from the
+    /// token before it.
+    starts_statement: bool,
+    /// Set on the `:` after a statement label, e.g. `a: for (...)`.
+    ends_label: bool,
+    /// Set on the `{` and `}` around the body of a function, class or arrow
+    /// function expression, whose `}` may end a statement.
+    expression_body: bool,
+    /// Set on the `[` of an array literal of primitives whose elements are
+    /// packed into filled lines.
+    packed: bool,
+    /// Set on the `(` and `)` around the head of a control statement, e.g.
+    /// `if (...)`, on the `{` and `}` around the body of a control statement,
+    /// and on a `while` that starts a loop rather than ending a `do`.
+    clause: Option<Clause>,
+    /// Set on a postfix `++` or `--`.
+    pub(crate) postfix: bool,
+    /// Set on a `*` that marks a generator method, e.g. `*m() {}`, rather than
+    /// multiplying.
+    generator_method: bool,
+    /// Set on the `(` around an async arrow function's parameters, e.g.
+    /// `async (a) => a`, as opposed to a call of a function named `async`.
+    async_arrow: bool,
+    /// Set on the parts of JSX elements.
+    pub(crate) jsx: Option<Jsx>,
+    /// Set on TypeScript syntax that would otherwise be taken for operators or
+    /// labels.
+    typescript: Option<TypeScript>,
+}
+
+impl<'a> Tok<'a> {
+    pub(crate) fn text(&self) -> String {
+        match self.replaced {
+            Some(ref s) => s.clone(),
+            None => self.token.to_string(),
+        }
+    }
 }
 
 type Stack<'a> = Vec<Tok<'a>>;
@@ -27,305 +127,634 @@ fn top_token<'a, 'b>(s: &'b Stack<'a>) -> Option<&'b Token<&'a str>> {
     s.last().map(|v| &v.token)
 }
 
-fn is_pre_array_literal_token(token: &Tok) -> bool {
-    match &token.token {
-        &Token::Keyword(Keyword::Case(_))
-        | &Token::Keyword(Keyword::Delete(_))
-        | &Token::Keyword(Keyword::Do(_))
-        | &Token::Keyword(Keyword::Else(_))
-        | &Token::Keyword(Keyword::In(_))
-        | &Token::Keyword(Keyword::InstanceOf(_))
-        | &Token::Keyword(Keyword::TypeOf(_))
-        | &Token::Keyword(Keyword::Void(_))
-        | &Token::Punct(Punct::Ampersand)
-        | &Token::Punct(Punct::AmpersandEqual)
-        | &Token::Punct(Punct::Asterisk)
-        | &Token::Punct(Punct::AsteriskEqual)
-        | &Token::Punct(Punct::Bang)
-        | &Token::Punct(Punct::BangDoubleEqual)
-        | &Token::Punct(Punct::BangEqual)
-        | &Token::Punct(Punct::Caret)
-        | &Token::Punct(Punct::CaretEqual)
-        | &Token::Punct(Punct::CloseBrace)
-        | &Token::Punct(Punct::Colon)
-        | &Token::Punct(Punct::Comma)
-        | &Token::Punct(Punct::Dash)
-        | &Token::Punct(Punct::DashEqual)
-        | &Token::Punct(Punct::DoubleAmpersand)
-        | &Token::Punct(Punct::DoubleAsterisk)
-        | &Token::Punct(Punct::DoubleAsteriskEqual)
-        | &Token::Punct(Punct::DoubleDash)
-        | &Token::Punct(Punct::DoubleEqual)
-        | &Token::Punct(Punct::DoubleGreaterThan)
-        | &Token::Punct(Punct::DoubleGreaterThanEqual)
-        | &Token::Punct(Punct::DoubleLessThan)
-        | &Token::Punct(Punct::DoubleLessThanEqual)
-        | &Token::Punct(Punct::DoublePipe)
-        | &Token::Punct(Punct::DoublePlus)
-        | &Token::Punct(Punct::Equal)
-        | &Token::Punct(Punct::ForwardSlash)
-        | &Token::Punct(Punct::ForwardSlashEqual)
-        | &Token::Punct(Punct::GreaterThan)
-        | &Token::Punct(Punct::GreaterThanEqual)
-        | &Token::Punct(Punct::LessThan)
-        | &Token::Punct(Punct::LessThanEqual)
-        | &Token::Punct(Punct::OpenBrace)
-        | &Token::Punct(Punct::Percent)
-        | &Token::Punct(Punct::PercentEqual)
-        | &Token::Punct(Punct::Pipe)
-        | &Token::Punct(Punct::PipeEqual)
-        | &Token::Punct(Punct::Plus)
-        | &Token::Punct(Punct::PlusEqual)
-        | &Token::Punct(Punct::QuestionMark)
-        | &Token::Punct(Punct::Tilde)
-        | &Token::Punct(Punct::TripleEqual)
-        | &Token::Punct(Punct::TripleGreaterThan)
-        | &Token::Punct(Punct::TripleGreaterThanEqual)
-        | &Token::Punct(Punct::SemiColon) => true,
-        _ => false,
-    }
+/// True if the top of the stack is the body of a `switch`, where `case` and
+/// `default` are labels.
+fn in_switch_body(s: &Stack) -> bool {
+    s.len() >= 2
+        && s[s.len() - 1].token == Token::Punct(Punct::OpenBrace)
+        && matches!(&s[s.len() - 2].token, &Token::Keyword(Keyword::Switch(_)))
 }
 
-fn starts_array_literal<'a>(token: &Tok, last_token: &Option<Tok>) -> bool {
-    if &token.token != &Token::Punct(Punct::OpenBracket) {
-        return false;
-    }
-    if let Some(ref t) = last_token {
-        is_pre_array_literal_token(t)
-    } else {
-        true
-    }
+fn in_class_body(s: &Stack) -> bool {
+    s.last()
+        .map(|v| v.brace_kind == BraceKind::ClassBody)
+        .unwrap_or(false)
 }
 
-fn prevent_asi_after_token(token: &Tok) -> bool {
-    match &token.token {
-        &Token::Keyword(Keyword::Delete(_))
-        | &Token::Keyword(Keyword::In(_))
-        | &Token::Keyword(Keyword::InstanceOf(_))
-        | &Token::Keyword(Keyword::TypeOf(_))
-        | &Token::Keyword(Keyword::Void(_))
-        | &Token::Keyword(Keyword::New(_))
-        | &Token::Punct(Punct::Ampersand)
-        | &Token::Punct(Punct::AmpersandEqual)
-        | &Token::Punct(Punct::Asterisk)
-        | &Token::Punct(Punct::AsteriskEqual)
-        | &Token::Punct(Punct::Bang)
-        | &Token::Punct(Punct::BangDoubleEqual)
-        | &Token::Punct(Punct::BangEqual)
-        | &Token::Punct(Punct::Caret)
-        | &Token::Punct(Punct::CaretEqual)
-        | &Token::Punct(Punct::Comma)
-        | &Token::Punct(Punct::Dash)
-        | &Token::Punct(Punct::DashEqual)
-        | &Token::Punct(Punct::DoubleAmpersand)
-        | &Token::Punct(Punct::DoubleAsterisk)
-        | &Token::Punct(Punct::DoubleAsteriskEqual)
-        | &Token::Punct(Punct::DoubleGreaterThan)
-        | &Token::Punct(Punct::DoubleGreaterThanEqual)
-        | &Token::Punct(Punct::DoubleLessThan)
-        | &Token::Punct(Punct::DoubleLessThanEqual)
-        | &Token::Punct(Punct::DoublePipe)
-        | &Token::Punct(Punct::Equal)
-        | &Token::Punct(Punct::ForwardSlash)
-        | &Token::Punct(Punct::ForwardSlashEqual)
-        | &Token::Punct(Punct::GreaterThan)
-        | &Token::Punct(Punct::GreaterThanEqual)
-        | &Token::Punct(Punct::LessThan)
-        | &Token::Punct(Punct::LessThanEqual)
-        | &Token::Punct(Punct::OpenParen)
-        | &Token::Punct(Punct::Percent)
-        | &Token::Punct(Punct::PercentEqual)
-        | &Token::Punct(Punct::Period)
-        | &Token::Punct(Punct::Pipe)
-        | &Token::Punct(Punct::PipeEqual)
-        | &Token::Punct(Punct::Plus)
-        | &Token::Punct(Punct::PlusEqual)
-        | &Token::Punct(Punct::Tilde)
-        | &Token::Punct(Punct::TripleEqual)
-        | &Token::Punct(Punct::TripleGreaterThan)
-        | &Token::Punct(Punct::TripleGreaterThanEqual) => true,
-        _ => false,
+/// True if the top of the stack is where statements go, rather than inside an
+/// expression.
+fn in_statement(s: &[Tok]) -> bool {
+    match s.last() {
+        None => true,
+        Some(v) => match v.token {
+            Token::Punct(Punct::OpenBrace) => !is_object_brace(v),
+            Token::Keyword(Keyword::Do(_)) => true,
+            _ => false,
+        },
     }
 }
 
-fn prevent_asi_before_token(token: &Tok) -> bool {
-    match &token.token {
-        &Token::Keyword(Keyword::In(_))
-        | &Token::Keyword(Keyword::InstanceOf(_))
-        | &Token::Punct(Punct::Ampersand)
-        | &Token::Punct(Punct::AmpersandEqual)
-        | &Token::Punct(Punct::Asterisk)
-        | &Token::Punct(Punct::AsteriskEqual)
-        | &Token::Punct(Punct::Bang)
-        | &Token::Punct(Punct::BangDoubleEqual)
-        | &Token::Punct(Punct::BangEqual)
-        | &Token::Punct(Punct::Caret)
-        | &Token::Punct(Punct::CaretEqual)
-        | &Token::Punct(Punct::Comma)
-        | &Token::Punct(Punct::Dash)
-        | &Token::Punct(Punct::DashEqual)
-        | &Token::Punct(Punct::DoubleAmpersand)
-        | &Token::Punct(Punct::DoubleAsterisk)
-        | &Token::Punct(Punct::DoubleAsteriskEqual)
-        | &Token::Punct(Punct::DoubleGreaterThan)
-        | &Token::Punct(Punct::DoubleGreaterThanEqual)
-        | &Token::Punct(Punct::DoubleLessThan)
-        | &Token::Punct(Punct::DoubleLessThanEqual)
-        | &Token::Punct(Punct::DoublePipe)
-        | &Token::Punct(Punct::Equal)
-        | &Token::Punct(Punct::ForwardSlash)
-        | &Token::Punct(Punct::ForwardSlashEqual)
-        | &Token::Punct(Punct::GreaterThan)
-        | &Token::Punct(Punct::GreaterThanEqual)
-        | &Token::Punct(Punct::LessThan)
-        | &Token::Punct(Punct::LessThanEqual)
-        | &Token::Punct(Punct::OpenParen)
-        | &Token::Punct(Punct::Percent)
-        | &Token::Punct(Punct::PercentEqual)
-        | &Token::Punct(Punct::Period)
-        | &Token::Punct(Punct::Pipe)
-        | &Token::Punct(Punct::PipeEqual)
-        | &Token::Punct(Punct::Plus)
-        | &Token::Punct(Punct::PlusEqual)
-        | &Token::Punct(Punct::Tilde)
-        | &Token::Punct(Punct::TripleEqual)
-        | &Token::Punct(Punct::TripleGreaterThan)
-        | &Token::Punct(Punct::TripleGreaterThanEqual) => true,
-        _ => false,
-    }
+pub(crate) fn is_inline(s: &Stack) -> bool {
+    s.last().map(|v| v.inline).unwrap_or(false)
 }
 
-fn is_identifier_like(token: &Tok) -> bool {
-    match &token.token {
-        &Token::Boolean(_)
-        | &Token::Ident(_)
-        | &Token::Keyword(_)
-        | &Token::Null
-        | &Token::Number(_) => true,
-        _ => false,
+fn is_object_brace(token: &Tok) -> bool {
+    match token.brace_kind {
+        BraceKind::ObjectLiteral | BraceKind::Pattern => true,
+        BraceKind::Block | BraceKind::ClassBody => false,
     }
 }
 
-fn is_asi(token: &Tok, last_token: &Option<Tok>) -> bool {
+/// Decide what the `OpenBrace` after `last_token` opens. `stack` does not include
+/// the `OpenBrace` yet. Class bodies are detected separately.
+fn brace_kind(last_token: &Option<Tok>, stack: &Stack) -> BraceKind {
     let t = if let Some(ref t) = last_token {
         t
     } else {
-        return false;
+        return BraceKind::Block;
     };
-    if token.start.line == t.start.line {
-        return false;
+    // `style={{...}}` in JSX.
+    if t.jsx.is_some() {
+        return BraceKind::ObjectLiteral;
     }
-    match &t.token {
-        &Token::Keyword(Keyword::Return(_)) | &Token::Keyword(Keyword::Yield(_)) => return true,
+    match t.typescript {
+        // `function f<T>(): A<T> {` and `interface I<T> {`.
+        Some(TypeScript::CloseAngle) => return BraceKind::Block,
+        // An object type, e.g. `let a: {b: number}`.
+        Some(TypeScript::TypeColon) => return BraceKind::ObjectLiteral,
         _ => (),
     }
-    if prevent_asi_after_token(t) || prevent_asi_before_token(token) {
-        return false;
-    }
-    true
-}
-
-fn is_line_delimiter(token: &Tok, stack: &Stack) -> bool {
-    if token.starts_array_literal {
-        return true;
-    }
-    match &token.token {
-        &Token::Punct(Punct::SemiColon) | &Token::Punct(Punct::Comma) => {
-            top_token(stack) != Some(&Token::Punct(Punct::OpenParen))
+    match &t.token {
+        &Token::Punct(Punct::CloseParen)
+        | &Token::Punct(Punct::CloseBrace)
+        | &Token::Punct(Punct::OpenBrace)
+        | &Token::Punct(Punct::SemiColon)
+        | &Token::Punct(Punct::EqualGreaterThan) => BraceKind::Block,
+        &Token::Punct(Punct::Colon) if t.ends_ternary_test => BraceKind::ObjectLiteral,
+        // `${{...}}`.
+        &Token::Template(Template::Head(_)) | &Token::Template(Template::Middle(_)) => {
+            BraceKind::ObjectLiteral
         }
-        &Token::Punct(Punct::OpenBrace) => true,
-        &Token::Punct(Punct::Colon) => match stack.last().map(|v| &v.token) {
-            Some(&Token::Keyword(Keyword::Case(_)))
-            | Some(&Token::Keyword(Keyword::Default(_))) => true,
-            _ => false,
+        &Token::Punct(Punct::Colon) => match stack.last() {
+            Some(v) if is_object_brace(v) => v.brace_kind,
+            // Labels and `case`s.
+            _ => BraceKind::Block,
         },
-        _ => false,
+        &Token::Punct(Punct::Comma) => match stack.last() {
+            Some(v) if v.brace_kind == BraceKind::Pattern => BraceKind::Pattern,
+            _ => BraceKind::ObjectLiteral,
+        },
+        &Token::Punct(_) => BraceKind::ObjectLiteral,
+        &Token::Keyword(Keyword::Const(_))
+        | &Token::Keyword(Keyword::Let(_))
+        | &Token::Keyword(Keyword::Var(_)) => BraceKind::Pattern,
+        &Token::Keyword(Keyword::Await(_))
+        | &Token::Keyword(Keyword::Case(_))
+        | &Token::Keyword(Keyword::Delete(_))
+        | &Token::Keyword(Keyword::In(_))
+        | &Token::Keyword(Keyword::InstanceOf(_))
+        | &Token::Keyword(Keyword::New(_))
+        | &Token::Keyword(Keyword::Return(_))
+        | &Token::Keyword(Keyword::Throw(_))
+        | &Token::Keyword(Keyword::TypeOf(_))
+        | &Token::Keyword(Keyword::Void(_))
+        | &Token::Keyword(Keyword::Yield(_)) => BraceKind::ObjectLiteral,
+        _ => BraceKind::Block,
     }
 }
 
-struct Writer {
-    buffer: String,
-    current: SourceCoord,
-    last_from: SourceCoord,
-    mappings: Vec<SourceMapping>,
-    indent: u32,
-}
-
-impl Writer {
-    fn new(indent: u32) -> Writer {
-        Writer {
-            buffer: String::new(),
-            current: SourceCoord {
-                line: SourceMapLine(0),
-                column: SourceMapColumn(0),
-            },
-            last_from: SourceCoord {
-                line: SourceMapLine(0),
-                column: SourceMapColumn(0),
-            },
-            mappings: Vec::new(),
-            indent: indent,
+/// Look ahead from just after an object literal's `{` for its `}`. Returns whether
+/// the literal is followed by `=` (so it is really a destructuring pattern), and
+/// whether it can be kept on one line within `max_width` columns.
+fn scan_object<'a, I>(tokens: &mut Lookahead<I>, max_width: Option<usize>) -> (bool, bool)
+where
+    I: Iterator<Item = Tok<'a>>,
+{
+    let mut depth = 0;
+    let mut width = 0;
+    let mut inline = max_width.is_some();
+    for i in 0..BRACE_LOOKAHEAD {
+        let t = if let Some(t) = tokens.peek_nth(i) {
+            t
+        } else {
+            break;
+        };
+        match &t.token {
+            &Token::Punct(Punct::OpenBrace)
+            | &Token::Punct(Punct::OpenBracket)
+            | &Token::Punct(Punct::OpenParen) => depth += 1,
+            &Token::Punct(Punct::CloseBrace) if depth == 0 => {
+                let pattern = tokens
+                    .peek_nth(i + 1)
+                    .map(|v| v.token == Token::Punct(Punct::Equal))
+                    .unwrap_or(false);
+                return (pattern, inline);
+            }
+            &Token::Punct(Punct::CloseBrace)
+            | &Token::Punct(Punct::CloseBracket)
+            | &Token::Punct(Punct::CloseParen) => {
+                if depth == 0 {
+                    break;
+                }
+                depth -= 1;
+            }
+            // Anything that could contain statements, or comments, must be
+            // laid out normally.
+            &Token::Comment(_)
+            | &Token::Keyword(Keyword::Class(_))
+            | &Token::Keyword(Keyword::Function(_))
+            | &Token::Punct(Punct::EqualGreaterThan)
+            | &Token::Punct(Punct::SemiColon) => inline = false,
+            _ => (),
         }
-    }
-    fn write_new(&mut self, s: &str) {
-        if self.mappings.is_empty() {
-            self.mappings.push(SourceMapping {
-                from: self.current,
-                to: self.last_from,
-            });
+        if t.start.line != t.end.line {
+            inline = false;
         }
-        self.update_current(s);
-    }
-    fn write(&mut self, s: &str, from: SourceCoord) {
-        self.last_from = from;
-        self.mappings.push(SourceMapping {
-            from: self.current,
-            to: self.last_from,
-        });
-        self.update_current(s);
-    }
-    fn write_indent(&mut self, level: u32) {
-        let count = level * self.indent;
-        for _ in 0..count {
-            self.buffer.push(' ');
+        width += (t.end.column.0.saturating_sub(t.start.column.0) + 1) as usize;
+        if max_width.map(|v| width > v + 1).unwrap_or(false) {
+            inline = false;
         }
-        self.current.column.0 += count;
     }
-    fn update_current(&mut self, s: &str) {
-        self.buffer.push_str(s);
-        for ch in s.chars() {
-            if ch == '\n' {
-                self.current.line.0 += 1;
-                self.current.column.0 = 0;
-            } else {
-                self.current.column.0 += ch.len_utf16() as u32;
+    (false, false)
+}
+
+/// Look ahead from just after a `for` loop's `(` for its `)`. Returns whether the
+/// header can be kept on one line, i.e. it holds no functions or comments.
+fn scan_for_header<'a, I>(tokens: &mut Lookahead<I>) -> bool
+where
+    I: Iterator<Item = Tok<'a>>,
+{
+    let mut depth = 0;
+    for i in 0..BRACE_LOOKAHEAD {
+        let t = if let Some(t) = tokens.peek_nth(i) {
+            t
+        } else {
+            break;
+        };
+        match &t.token {
+            &Token::Punct(Punct::OpenBrace)
+            | &Token::Punct(Punct::OpenBracket)
+            | &Token::Punct(Punct::OpenParen) => depth += 1,
+            &Token::Punct(Punct::CloseParen) if depth == 0 => return true,
+            &Token::Punct(Punct::CloseBrace)
+            | &Token::Punct(Punct::CloseBracket)
+            | &Token::Punct(Punct::CloseParen) => {
+                if depth == 0 {
+                    break;
+                }
+                depth -= 1;
             }
+            &Token::Comment(_)
+            | &Token::Keyword(Keyword::Class(_))
+            | &Token::Keyword(Keyword::Function(_))
+            | &Token::Punct(Punct::EqualGreaterThan) => break,
+            _ => (),
         }
     }
+    false
 }
 
-fn append_newline(token: &Tok, stack: &Stack, out: &mut Writer) -> bool {
-    if is_line_delimiter(token, stack) {
-        out.write_new("\n");
-        return true;
+/// Look ahead from just after a `(` for its `)`. Returns whether it is followed by
+/// `=>`, i.e. the parentheses hold an arrow function's parameters.
+fn is_arrow_params<'a, I>(tokens: &mut Lookahead<I>) -> bool
+where
+    I: Iterator<Item = Tok<'a>>,
+{
+    let mut depth = 0;
+    let mut i = 0;
+    while let Some(t) = tokens.peek_nth(i) {
+        i += 1;
+        match &t.token {
+            &Token::Punct(Punct::OpenBrace)
+            | &Token::Punct(Punct::OpenBracket)
+            | &Token::Punct(Punct::OpenParen) => depth += 1,
+            &Token::Punct(Punct::CloseParen) if depth == 0 => break,
+            &Token::Punct(Punct::CloseBrace)
+            | &Token::Punct(Punct::CloseBracket)
+            | &Token::Punct(Punct::CloseParen) => {
+                if depth == 0 {
+                    return false;
+                }
+                depth -= 1;
+            }
+            &Token::EoF => return false,
+            _ => (),
+        }
+    }
+    while let Some(t) = tokens.peek_nth(i) {
+        i += 1;
+        match t.token {
+            Token::Comment(_) => (),
+            Token::Punct(Punct::EqualGreaterThan) => return true,
+            _ => return false,
+        }
     }
     false
 }
 
-fn need_space_after(token: &Tok, last_token: &Option<Tok>) -> bool {
-    if let Some(t) = last_token.as_ref() {
+/// Look ahead from just after a `<` for the `>` that closes it, if it starts
+/// TypeScript type parameters or arguments, e.g. `Map<K, Array<V>>` or
+/// `f<T>(x)`. `after_operand` is false if the `<` cannot be a comparison, in
+/// which case it must start a generic arrow function's type parameters, e.g.
+/// `<T>(x: T) => x`. If it does start type parameters or arguments, marks
+/// the nested `<` and all the `>` that close them, splitting operators such as
+/// `>>` and `>=` into their parts, and returns true.
+fn scan_type_arguments<'a, I>(tokens: &mut Lookahead<I>, after_operand: bool) -> bool
+where
+    I: Iterator<Item = Tok<'a>>,
+{
+    let mut depth = 1;
+    let mut nesting = 0;
+    let mut opens = Vec::new();
+    let mut closes = Vec::new();
+    let mut end = None;
+    for i in 0..BRACE_LOOKAHEAD {
+        let t = if let Some(t) = tokens.peek_nth(i) {
+            t
+        } else {
+            return false;
+        };
         match &t.token {
-            &Token::Keyword(Keyword::Do(_))
-            | &Token::Keyword(Keyword::For(_))
-            | &Token::Keyword(Keyword::While(_))
+            &Token::Punct(Punct::OpenBrace)
+            | &Token::Punct(Punct::OpenBracket)
+            | &Token::Punct(Punct::OpenParen) => nesting += 1,
+            &Token::Punct(Punct::CloseBrace)
+            | &Token::Punct(Punct::CloseBracket)
+            | &Token::Punct(Punct::CloseParen) => {
+                if nesting == 0 {
+                    return false;
+                }
+                nesting -= 1;
+            }
+            &Token::Punct(Punct::LessThan) => {
+                depth += 1;
+                opens.push(i);
+            }
+            &Token::Punct(Punct::GreaterThan)
+            | &Token::Punct(Punct::GreaterThanEqual)
+            | &Token::Punct(Punct::DoubleGreaterThan)
+            | &Token::Punct(Punct::DoubleGreaterThanEqual)
+            | &Token::Punct(Punct::TripleGreaterThan)
+            | &Token::Punct(Punct::TripleGreaterThanEqual) => {
+                let text = t.token.to_string();
+                let count = text.matches('>').count();
+                // What is left after the last `>` must follow the type
+                // arguments, e.g. the `=` of `let m: Map<K, V>= x`.
+                if count > depth || (count < depth && text.ends_with('=')) {
+                    return false;
+                }
+                depth -= count;
+                closes.push(i);
+                if depth == 0 {
+                    end = Some((i, text.ends_with('=')));
+                    break;
+                }
+            }
+            &Token::Boolean(_)
+            | &Token::Ident(_)
+            | &Token::Keyword(_)
+            | &Token::Null
+            | &Token::String(_)
+            | &Token::Punct(Punct::Ampersand)
+            | &Token::Punct(Punct::Comma)
+            | &Token::Punct(Punct::Ellipsis)
+            | &Token::Punct(Punct::Equal)
+            | &Token::Punct(Punct::EqualGreaterThan)
+            | &Token::Punct(Punct::Period)
+            | &Token::Punct(Punct::Pipe) => (),
+            // Object and function types, e.g. `Array<{a?: number}>`. Elsewhere
+            // these are much more likely to be part of a conditional
+            // expression, e.g. `a < 0 ? b : c > d`.
+            &Token::Number(_)
+            | &Token::Punct(Punct::Colon)
+            | &Token::Punct(Punct::QuestionMark)
+            | &Token::Punct(Punct::SemiColon)
+                if nesting > 0 => {}
+            _ => return false,
+        }
+    }
+    let (end, assign) = if let Some(end) = end {
+        end
+    } else {
+        return false;
+    };
+    // What may follow type arguments, as opposed to the right-hand side of a
+    // `>` comparison.
+    let end_line = tokens.buffer[end].end.line;
+    let follows = assign
+        || match tokens.peek_nth(end + 1) {
+            Some(t) if !after_operand => t.token == Token::Punct(Punct::OpenParen),
+            // The end of a statement, e.g. `let m: Map<K, V>` before ASI.
+            Some(t) if t.start.line != end_line => true,
+            Some(t) => matches!(
+                &t.token,
+                &Token::Punct(Punct::Ampersand)
+                    | &Token::Punct(Punct::CloseBrace)
+                    | &Token::Punct(Punct::CloseBracket)
+                    | &Token::Punct(Punct::CloseParen)
+                    | &Token::Punct(Punct::Colon)
+                    | &Token::Punct(Punct::Comma)
+                    | &Token::Punct(Punct::Equal)
+                    | &Token::Punct(Punct::EqualGreaterThan)
+                    | &Token::Punct(Punct::OpenBrace)
+                    | &Token::Punct(Punct::OpenBracket)
+                    | &Token::Punct(Punct::OpenParen)
+                    | &Token::Punct(Punct::Period)
+                    | &Token::Punct(Punct::Pipe)
+                    | &Token::Punct(Punct::QuestionMark)
+                    | &Token::Punct(Punct::SemiColon)
+                    | &Token::Keyword(Keyword::Extends(_))
+                    | &Token::Keyword(Keyword::Implements(_))
+                    | &Token::Template(_)
+                    | &Token::EoF
+            ),
+            None => true,
+        };
+    if !follows {
+        return false;
+    }
+    for i in opens {
+        tokens.buffer[i].typescript = Some(TypeScript::OpenAngle);
+    }
+    // From the end, so that splitting a token doesn't move the others.
+    for i in closes.into_iter().rev() {
+        let joined = tokens.buffer.remove(i).unwrap();
+        let text = joined.token.to_string();
+        for (n, c) in text.chars().enumerate() {
+            let mut start = joined.start;
+            start.column.0 += n as u32;
+            let mut end = start;
+            end.column.0 += 1;
+            let part = if c == '>' {
+                Tok {
+                    token: Token::Punct(Punct::GreaterThan),
+                    typescript: Some(TypeScript::CloseAngle),
+                    start,
+                    end,
+                    ..joined.clone()
+                }
+            } else {
+                Tok {
+                    token: Token::Punct(Punct::Equal),
+                    start,
+                    end,
+                    ..joined.clone()
+                }
+            };
+            tokens.buffer.insert(i + n, part);
+        }
+    }
+    true
+}
+
+fn is_primitive_literal(token: &Tok) -> bool {
+    match &token.token {
+        &Token::Boolean(_) | &Token::Null | &Token::Number(_) | &Token::String(_) => {
+            token.start.line == token.end.line
+        }
+        _ => false,
+    }
+}
+
+/// Look ahead from just after an array literal's `[` for its `]`. Returns the
+/// width of the literal's contents laid out on one line, or None if some element
+/// is not a primitive literal, optionally negated. `spaced_signs` is set if a
+/// sign is followed by a space, as in JS but not JSON.
+pub(crate) fn scan_array<'a, I>(tokens: &mut Lookahead<I>, spaced_signs: bool) -> Option<usize>
+where
+    I: Iterator<Item = Tok<'a>>,
+{
+    let mut width = 0;
+    let mut element_start = true;
+    for i in 0..ARRAY_LOOKAHEAD {
+        let t = tokens.peek_nth(i)?;
+        match &t.token {
+            &Token::Punct(Punct::CloseBracket) => return Some(width),
+            &Token::Punct(Punct::Comma) => {
+                width += 2;
+                element_start = true;
+                continue;
+            }
+            &Token::Punct(Punct::Dash) | &Token::Punct(Punct::Plus) if element_start => {
+                width += 1 + spaced_signs as usize;
+            }
+            _ if is_primitive_literal(t) => {
+                width += (t.end.column.0 - t.start.column.0) as usize;
+            }
+            _ => return None,
+        }
+        element_start = false;
+    }
+    None
+}
+
+/// The width of the array element starting at `token`, with the `, ` after it
+/// unless it is the last element. `spaced_signs` is as for `scan_array`.
+pub(crate) fn element_width<'a, I>(
+    token: &Tok,
+    tokens: &mut Lookahead<I>,
+    spaced_signs: bool,
+) -> usize
+where
+    I: Iterator<Item = Tok<'a>>,
+{
+    let mut width = (token.end.column.0 - token.start.column.0) as usize;
+    // A sign at the start of the element, e.g. `-2`.
+    let mut after_sign = matches!(
+        &token.token,
+        &Token::Punct(Punct::Dash) | &Token::Punct(Punct::Plus)
+    );
+    let mut i = 0;
+    while let Some(t) = tokens.peek_nth(i) {
+        match t.token {
+            Token::Punct(Punct::Comma) => return width + 1,
+            Token::Punct(Punct::CloseBracket) => break,
+            _ if after_sign && !spaced_signs => {
+                width += (t.end.column.0 - t.start.column.0) as usize
+            }
+            _ => width += 1 + (t.end.column.0 - t.start.column.0) as usize,
+        }
+        after_sign = false;
+        i += 1;
+    }
+    width
+}
+
+fn is_operand_end(token: &Token<&str>) -> bool {
+    matches!(
+        token,
+        &Token::Ident(_)
+            | &Token::Punct(Punct::CloseParen)
+            | &Token::Punct(Punct::CloseBracket)
+            | &Token::Template(_)
+    )
+}
+
+/// True if `token` continues a method chain after `last_token`.
+fn continues_chain(token: &Tok, last_token: &Option<Tok>) -> bool {
+    let last = last_token.as_ref().map(|v| &v.token);
+    match &token.token {
+        &Token::Punct(Punct::Period) => true,
+        &Token::Ident(_) | &Token::Punct(Punct::Hash) => matches!(
+            last,
+            Some(&Token::Punct(Punct::Period)) | Some(&Token::Punct(Punct::Hash))
+        ),
+        // `?.(` and `?.[` as well as ordinary calls and indexing.
+        &Token::Punct(Punct::OpenParen) | &Token::Punct(Punct::OpenBracket) => match last {
+            Some(&Token::Punct(Punct::Period)) => true,
+            Some(t) => is_operand_end(t),
+            None => false,
+        },
+        &Token::Template(_) => last.map(is_operand_end).unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Look ahead from just after a `.` that follows a call, at column `column`, to the
+/// end of the method chain. Returns true if the chain makes at least two calls
+/// after the one before the `.` and would extend beyond `max_width` columns.
+fn scan_chain<'a, I>(tokens: &mut Lookahead<I>, column: usize, max_width: usize) -> bool
+where
+    I: Iterator<Item = Tok<'a>>,
+{
+    let mut depth = 0;
+    let mut width = column + 1;
+    let mut calls = 1;
+    let mut last_period = true;
+    let mut last_operand_end = false;
+    let mut last_call = false;
+    for i in 0..BRACE_LOOKAHEAD {
+        let t = if let Some(t) = tokens.peek_nth(i) {
+            t
+        } else {
+            return false;
+        };
+        if depth > 0 {
+            match &t.token {
+                &Token::Punct(Punct::OpenBrace)
+                | &Token::Punct(Punct::OpenBracket)
+                | &Token::Punct(Punct::OpenParen) => depth += 1,
+                &Token::Punct(Punct::CloseBrace)
+                | &Token::Punct(Punct::CloseBracket)
+                | &Token::Punct(Punct::CloseParen) => depth -= 1,
+                &Token::Punct(Punct::Comma) => width += 1,
+                _ => (),
+            }
+        } else {
+            let continues = match &t.token {
+                &Token::Punct(Punct::Period) => {
+                    if last_call {
+                        calls += 1;
+                    }
+                    true
+                }
+                &Token::Ident(_) | &Token::Keyword(_) | &Token::Punct(Punct::Hash) => last_period,
+                &Token::Punct(Punct::OpenParen) | &Token::Punct(Punct::OpenBracket) => {
+                    depth += 1;
+                    last_operand_end || last_period
+                }
+                &Token::Template(_) => last_operand_end,
+                _ => false,
+            };
+            if !continues {
+                return false;
+            }
+        }
+        if depth == 0 {
+            last_period = matches!(
+                &t.token,
+                &Token::Punct(Punct::Period) | &Token::Punct(Punct::Hash)
+            );
+            last_call = t.token == Token::Punct(Punct::CloseParen);
+            last_operand_end = match &t.token {
+                &Token::Keyword(_) => true,
+                token => is_operand_end(token),
+            };
+        }
+        width += if t.start.line == t.end.line {
+            (t.end.column.0 - t.start.column.0) as usize
+        } else {
+            max_width
+        };
+        if calls >= 2 && width > max_width {
+            return true;
+        }
+    }
+    calls >= 2
+}
+
+/// Look ahead from just after an operator at column `column` to the end of the
+/// expression. Returns true if the expression would extend beyond `max_width`
+/// columns.
+fn scan_expression<'a, I>(tokens: &mut Lookahead<I>, column: usize, max_width: usize) -> bool
+where
+    I: Iterator<Item = Tok<'a>>,
+{
+    let mut depth = 0;
+    let mut width = column;
+    let mut last = None;
+    for i in 0..BRACE_LOOKAHEAD {
+        let t = if let Some(t) = tokens.peek_nth(i) {
+            t
+        } else {
+            return false;
+        };
+        match &t.token {
+            &Token::EoF => return false,
+            &Token::Punct(Punct::OpenBrace)
+            | &Token::Punct(Punct::OpenBracket)
+            | &Token::Punct(Punct::OpenParen) => depth += 1,
+            &Token::Punct(Punct::CloseBrace)
+            | &Token::Punct(Punct::CloseBracket)
+            | &Token::Punct(Punct::CloseParen) => {
+                if depth == 0 {
+                    return false;
+                }
+                depth -= 1;
+            }
+            &Token::Punct(Punct::SemiColon) | &Token::Punct(Punct::Comma) if depth == 0 => {
+                return false;
+            }
+            _ if depth == 0 && is_asi(t, &last) => return false,
+            _ => (),
+        }
+        width += 1 + if t.start.line == t.end.line {
+            (t.end.column.0 - t.start.column.0) as usize
+        } else {
+            max_width
+        };
+        if width > max_width {
+            return true;
+        }
+        last = Some(t.clone());
+    }
+    true
+}
+
+fn is_pre_array_literal_token(token: &Tok) -> bool {
+    matches!(
+        &token.token,
+        &Token::Keyword(Keyword::Case(_))
+            | &Token::Keyword(Keyword::Delete(_))
+            | &Token::Keyword(Keyword::Do(_))
+            | &Token::Keyword(Keyword::Else(_))
+            | &Token::Keyword(Keyword::In(_))
+            | &Token::Keyword(Keyword::InstanceOf(_))
+            | &Token::Keyword(Keyword::Return(_))
+            | &Token::Keyword(Keyword::Throw(_))
+            | &Token::Keyword(Keyword::TypeOf(_))
+            | &Token::Keyword(Keyword::Void(_))
+            | &Token::Keyword(Keyword::Yield(_))
             | &Token::Punct(Punct::Ampersand)
             | &Token::Punct(Punct::AmpersandEqual)
             | &Token::Punct(Punct::Asterisk)
             | &Token::Punct(Punct::AsteriskEqual)
+            | &Token::Punct(Punct::Bang)
             | &Token::Punct(Punct::BangDoubleEqual)
             | &Token::Punct(Punct::BangEqual)
             | &Token::Punct(Punct::Caret)
             | &Token::Punct(Punct::CaretEqual)
+            | &Token::Punct(Punct::CloseBrace)
             | &Token::Punct(Punct::Colon)
             | &Token::Punct(Punct::Comma)
             | &Token::Punct(Punct::Dash)
@@ -333,19 +762,25 @@ fn need_space_after(token: &Tok, last_token: &Option<Tok>) -> bool {
             | &Token::Punct(Punct::DoubleAmpersand)
             | &Token::Punct(Punct::DoubleAsterisk)
             | &Token::Punct(Punct::DoubleAsteriskEqual)
+            | &Token::Punct(Punct::DoubleDash)
             | &Token::Punct(Punct::DoubleEqual)
             | &Token::Punct(Punct::DoubleGreaterThan)
             | &Token::Punct(Punct::DoubleGreaterThanEqual)
             | &Token::Punct(Punct::DoubleLessThan)
             | &Token::Punct(Punct::DoubleLessThanEqual)
             | &Token::Punct(Punct::DoublePipe)
+            | &Token::Punct(Punct::DoublePlus)
             | &Token::Punct(Punct::Equal)
+            | &Token::Punct(Punct::EqualGreaterThan)
             | &Token::Punct(Punct::ForwardSlash)
             | &Token::Punct(Punct::ForwardSlashEqual)
             | &Token::Punct(Punct::GreaterThan)
             | &Token::Punct(Punct::GreaterThanEqual)
             | &Token::Punct(Punct::LessThan)
             | &Token::Punct(Punct::LessThanEqual)
+            | &Token::Punct(Punct::OpenBrace)
+            | &Token::Punct(Punct::OpenBracket)
+            | &Token::Punct(Punct::OpenParen)
             | &Token::Punct(Punct::Percent)
             | &Token::Punct(Punct::PercentEqual)
             | &Token::Punct(Punct::Pipe)
@@ -353,268 +788,2393 @@ fn need_space_after(token: &Tok, last_token: &Option<Tok>) -> bool {
             | &Token::Punct(Punct::Plus)
             | &Token::Punct(Punct::PlusEqual)
             | &Token::Punct(Punct::QuestionMark)
-            | &Token::Punct(Punct::SemiColon)
+            | &Token::Punct(Punct::Tilde)
             | &Token::Punct(Punct::TripleEqual)
             | &Token::Punct(Punct::TripleGreaterThan)
-            | &Token::Punct(Punct::TripleGreaterThanEqual) => return true,
-            &Token::Number(_) => {
-                if let &Token::Punct(Punct::Period) = &token.token {
-                    return true;
-                }
-            }
-            &Token::Keyword(Keyword::Break(_))
-            | &Token::Keyword(Keyword::Continue(_))
-            | &Token::Keyword(Keyword::Return(_)) => match &token.token {
-                &Token::Punct(Punct::Period) | &Token::Punct(Punct::SemiColon) => (),
-                _ => return true,
-            },
-            &Token::Keyword(Keyword::Debugger(_))
-            | &Token::Keyword(Keyword::Default(_))
-            | &Token::Keyword(Keyword::This(_)) => (),
-            &Token::Keyword(_) => match &token.token {
-                &Token::Punct(Punct::Period) => (),
-                _ => return true,
-            },
-            &Token::Punct(Punct::CloseParen) => match &token.token {
-                &Token::Punct(Punct::CloseParen)
-                | &Token::Punct(Punct::CloseBracket)
-                | &Token::Punct(Punct::SemiColon)
-                | &Token::Punct(Punct::Comma)
-                | &Token::Punct(Punct::Period) => (),
-                _ => return true,
-            },
-            &Token::Ident(_) => {
-                if let &Token::Punct(Punct::OpenBrace) = &token.token {
-                    return true;
-                }
+            | &Token::Punct(Punct::TripleGreaterThanEqual)
+            | &Token::Punct(Punct::SemiColon)
+    )
+}
+
+fn starts_array_literal(token: &Tok, last_token: &Option<Tok>, stack: &Stack) -> bool {
+    // In a class body, `[` starts a computed member name.
+    if token.token != Token::Punct(Punct::OpenBracket) || in_class_body(stack) {
+        return false;
+    }
+    if let Some(ref t) = last_token {
+        // So it does at the start of an object literal property.
+        if stack.last().map(is_object_brace).unwrap_or(false) {
+            match &t.token {
+                &Token::Punct(Punct::OpenBrace) | &Token::Punct(Punct::Comma) => return false,
+                _ => (),
             }
-            _ => (),
         }
-        if is_identifier_like(token) && is_identifier_like(t) {
-            return true;
+        is_pre_array_literal_token(t)
+    } else {
+        true
+    }
+}
+
+fn prevent_asi_after_token(token: &Tok) -> bool {
+    // `let m: Map<K, V>` can end a statement.
+    if token.typescript == Some(TypeScript::CloseAngle) {
+        return false;
+    }
+    matches!(
+        &token.token,
+        &Token::Keyword(Keyword::Delete(_))
+            | &Token::Keyword(Keyword::In(_))
+            | &Token::Keyword(Keyword::InstanceOf(_))
+            | &Token::Keyword(Keyword::TypeOf(_))
+            | &Token::Keyword(Keyword::Void(_))
+            | &Token::Keyword(Keyword::New(_))
+            | &Token::Punct(Punct::Ampersand)
+            | &Token::Punct(Punct::AmpersandEqual)
+            | &Token::Punct(Punct::Asterisk)
+            | &Token::Punct(Punct::AsteriskEqual)
+            | &Token::Punct(Punct::Bang)
+            | &Token::Punct(Punct::BangDoubleEqual)
+            | &Token::Punct(Punct::BangEqual)
+            | &Token::Punct(Punct::Caret)
+            | &Token::Punct(Punct::CaretEqual)
+            | &Token::Punct(Punct::Comma)
+            | &Token::Punct(Punct::Dash)
+            | &Token::Punct(Punct::DashEqual)
+            | &Token::Punct(Punct::DoubleAmpersand)
+            | &Token::Punct(Punct::DoubleAsterisk)
+            | &Token::Punct(Punct::DoubleAsteriskEqual)
+            | &Token::Punct(Punct::DoubleGreaterThan)
+            | &Token::Punct(Punct::DoubleGreaterThanEqual)
+            | &Token::Punct(Punct::DoubleLessThan)
+            | &Token::Punct(Punct::DoubleLessThanEqual)
+            | &Token::Punct(Punct::DoublePipe)
+            | &Token::Punct(Punct::Equal)
+            | &Token::Punct(Punct::EqualGreaterThan)
+            | &Token::Punct(Punct::ForwardSlash)
+            | &Token::Punct(Punct::ForwardSlashEqual)
+            | &Token::Punct(Punct::GreaterThan)
+            | &Token::Punct(Punct::GreaterThanEqual)
+            | &Token::Punct(Punct::LessThan)
+            | &Token::Punct(Punct::LessThanEqual)
+            | &Token::Punct(Punct::OpenParen)
+            | &Token::Punct(Punct::Percent)
+            | &Token::Punct(Punct::PercentEqual)
+            | &Token::Punct(Punct::Period)
+            | &Token::Punct(Punct::Pipe)
+            | &Token::Punct(Punct::PipeEqual)
+            | &Token::Punct(Punct::Plus)
+            | &Token::Punct(Punct::PlusEqual)
+            | &Token::Punct(Punct::Tilde)
+            | &Token::Punct(Punct::TripleEqual)
+            | &Token::Punct(Punct::TripleGreaterThan)
+            | &Token::Punct(Punct::TripleGreaterThanEqual)
+    )
+}
+
+fn prevent_asi_before_token(token: &Tok) -> bool {
+    matches!(
+        &token.token,
+        &Token::Keyword(Keyword::In(_))
+            | &Token::Keyword(Keyword::InstanceOf(_))
+            | &Token::Punct(Punct::Ampersand)
+            | &Token::Punct(Punct::AmpersandEqual)
+            | &Token::Punct(Punct::Asterisk)
+            | &Token::Punct(Punct::AsteriskEqual)
+            | &Token::Punct(Punct::Bang)
+            | &Token::Punct(Punct::BangDoubleEqual)
+            | &Token::Punct(Punct::BangEqual)
+            | &Token::Punct(Punct::Caret)
+            | &Token::Punct(Punct::CaretEqual)
+            | &Token::Punct(Punct::Comma)
+            | &Token::Punct(Punct::Dash)
+            | &Token::Punct(Punct::DashEqual)
+            | &Token::Punct(Punct::DoubleAmpersand)
+            | &Token::Punct(Punct::DoubleAsterisk)
+            | &Token::Punct(Punct::DoubleAsteriskEqual)
+            | &Token::Punct(Punct::DoubleGreaterThan)
+            | &Token::Punct(Punct::DoubleGreaterThanEqual)
+            | &Token::Punct(Punct::DoubleLessThan)
+            | &Token::Punct(Punct::DoubleLessThanEqual)
+            | &Token::Punct(Punct::DoublePipe)
+            | &Token::Punct(Punct::Equal)
+            | &Token::Punct(Punct::EqualGreaterThan)
+            | &Token::Punct(Punct::ForwardSlash)
+            | &Token::Punct(Punct::ForwardSlashEqual)
+            | &Token::Punct(Punct::GreaterThan)
+            | &Token::Punct(Punct::GreaterThanEqual)
+            | &Token::Punct(Punct::LessThan)
+            | &Token::Punct(Punct::LessThanEqual)
+            | &Token::Punct(Punct::OpenParen)
+            | &Token::Punct(Punct::Percent)
+            | &Token::Punct(Punct::PercentEqual)
+            | &Token::Punct(Punct::Period)
+            | &Token::Punct(Punct::Pipe)
+            | &Token::Punct(Punct::PipeEqual)
+            | &Token::Punct(Punct::Plus)
+            | &Token::Punct(Punct::PlusEqual)
+            | &Token::Punct(Punct::Tilde)
+            | &Token::Punct(Punct::TripleEqual)
+            | &Token::Punct(Punct::TripleGreaterThan)
+            | &Token::Punct(Punct::TripleGreaterThanEqual)
+    )
+}
+
+fn is_identifier_like(token: &Tok) -> bool {
+    matches!(
+        &token.token,
+        &Token::Boolean(_)
+            | &Token::Ident(_)
+            | &Token::Keyword(_)
+            | &Token::Null
+            | &Token::Number(_)
+    )
+}
+
+/// True if `token` starts a statement, judging by the token before it.
+fn starts_statement(token: &Tok, last_token: &Option<Tok>, stack: &Stack) -> bool {
+    if !in_statement(stack) {
+        return false;
+    }
+    let t = match last_token {
+        Some(t) => t,
+        None => return true,
+    };
+    match &t.token {
+        &Token::Punct(Punct::SemiColon)
+        | &Token::Punct(Punct::OpenBrace)
+        | &Token::Punct(Punct::CloseBrace)
+        | &Token::Keyword(Keyword::Do(_))
+        | &Token::Keyword(Keyword::Else(_)) => true,
+        &Token::Punct(Punct::Colon) => t.ends_case || t.ends_label,
+        &Token::Punct(Punct::CloseParen) if t.clause.is_some() => true,
+        _ => is_asi(token, last_token),
+    }
+}
+
+pub(crate) fn is_asi(token: &Tok, last_token: &Option<Tok>) -> bool {
+    let t = if let Some(ref t) = last_token {
+        t
+    } else {
+        return false;
+    };
+    if token.start.line == t.end.line {
+        return false;
+    }
+    match &t.token {
+        &Token::Keyword(Keyword::Return(_)) | &Token::Keyword(Keyword::Yield(_)) => return true,
+        _ => (),
+    }
+    if follows_postfix(token, t) {
+        return true;
+    }
+    if prevent_asi_after_token(t) || prevent_asi_before_token(token) {
+        return false;
+    }
+    // A JSX element in parentheses, e.g. `return (\n<div />\n)`.
+    if t.jsx.is_some()
+        && matches!(
+            &token.token,
+            &Token::Punct(Punct::CloseBracket) | &Token::Punct(Punct::CloseParen)
+        )
+    {
+        return false;
+    }
+    true
+}
+
+/// True if a statement directly in a block can end with `t`.
+fn can_end_statement(t: &Tok, stack: &Stack) -> bool {
+    let ends_statement = match &t.token {
+        &Token::Boolean(_)
+        | &Token::Ident(_)
+        | &Token::Keyword(Keyword::Break(_))
+        | &Token::Keyword(Keyword::Continue(_))
+        | &Token::Keyword(Keyword::Debugger(_))
+        | &Token::Keyword(Keyword::Return(_))
+        | &Token::Keyword(Keyword::Super(_))
+        | &Token::Keyword(Keyword::This(_))
+        | &Token::Null
+        | &Token::Number(_)
+        | &Token::Punct(Punct::CloseBracket)
+        | &Token::Punct(Punct::DoubleDash)
+        | &Token::Punct(Punct::DoublePlus)
+        | &Token::RegEx(_)
+        | &Token::String(_)
+        | &Token::Template(_) => true,
+        &Token::Punct(Punct::CloseParen) => matches!(t.clause, None | Some(Clause::DoWhile)),
+        &Token::Punct(Punct::CloseBrace) => is_object_brace(t) || t.expression_body,
+        // The end of TypeScript type arguments, e.g. `let m: Map<K, V>`.
+        &Token::Punct(Punct::GreaterThan) => t.typescript.is_some(),
+        _ => false,
+    };
+    // Not e.g. class fields.
+    let in_block = match stack.last() {
+        Some(v) => match v.token {
+            Token::Punct(Punct::OpenBrace) => v.brace_kind == BraceKind::Block,
+            Token::Keyword(Keyword::Do(_)) => true,
+            _ => false,
+        },
+        None => true,
+    };
+    ends_statement && in_block
+}
+
+/// True if automatic semicolon insertion puts a `;` between `last_token` and
+/// `token`. This errs on the side of false, since a `;` that ASI would not have
+/// inserted changes the meaning of the code.
+fn needs_semicolon(token: &Tok, last_token: &Option<Tok>, stack: &Stack) -> bool {
+    let t = if let Some(ref t) = last_token {
+        t
+    } else {
+        return false;
+    };
+    if !can_end_statement(t, stack) {
+        return false;
+    }
+    if token.token == Token::Punct(Punct::CloseBrace) {
+        return !stack.is_empty();
+    }
+    begins_statement(token, t)
+}
+
+/// True if `t` is a postfix `++` or `--` and `token` cannot follow it, e.g.
+/// the `(` of a call.
+fn follows_postfix(token: &Tok, t: &Tok) -> bool {
+    t.postfix
+        && matches!(
+            &token.token,
+            &Token::Punct(Punct::OpenParen)
+                | &Token::Punct(Punct::OpenBracket)
+                | &Token::Punct(Punct::Period)
+                | &Token::Template(_)
+        )
+}
+
+/// True if `token` cannot continue an expression ending with `t`, so it must
+/// start a new statement.
+fn begins_statement(token: &Tok, t: &Tok) -> bool {
+    if token.start.line == t.end.line || token.token == Token::Punct(Punct::SemiColon) {
+        return false;
+    }
+    // A line break ends `return`, `break` and `continue` whatever follows.
+    if follows_postfix(token, t)
+        || matches!(
+            &t.token,
+            &Token::Keyword(Keyword::Return(_))
+                | &Token::Keyword(Keyword::Break(_))
+                | &Token::Keyword(Keyword::Continue(_))
+        )
+    {
+        return true;
+    }
+    match &token.token {
+        &Token::Keyword(Keyword::In(_)) | &Token::Keyword(Keyword::InstanceOf(_)) => false,
+        &Token::Boolean(_)
+        | &Token::Ident(_)
+        | &Token::Keyword(_)
+        | &Token::Null
+        | &Token::Number(_)
+        | &Token::Punct(Punct::Bang)
+        | &Token::Punct(Punct::DoubleDash)
+        | &Token::Punct(Punct::DoublePlus)
+        | &Token::Punct(Punct::Tilde)
+        | &Token::String(_) => true,
+        _ => false,
+    }
+}
+
+/// True if `tokens`, the first tokens of a statement, start a function or class
+/// declaration, e.g. `function f`, `async function f` or `export default class`.
+fn starts_declaration(tokens: &[Tok]) -> bool {
+    let mut tokens = tokens.iter().map(|v| &v.token).skip_while(|t| {
+        matches!(
+            t,
+            &&Token::Keyword(Keyword::Export(_)) | &&Token::Keyword(Keyword::Default(_))
+        )
+    });
+    match tokens.next() {
+        Some(&Token::Keyword(Keyword::Function(_))) | Some(&Token::Keyword(Keyword::Class(_))) => {
+            true
+        }
+        Some(t) if t.matches_ident_str("async") => {
+            matches!(tokens.next(), Some(&Token::Keyword(Keyword::Function(_))))
+        }
+        _ => false,
+    }
+}
+
+/// True if `token` is a `,` after a function in an array or object literal
+/// that is passed to a call at the top level, like the module tables of
+/// bundles, e.g. `f([function(e){...}, ...])` or `f({12: function(e){...}, ...})`.
+fn ends_module_wrapper(
+    token: &Tok,
+    last_token: &Option<Tok>,
+    next_token: Option<&Tok>,
+    stack: &Stack,
+) -> bool {
+    if token.token != Token::Punct(Punct::Comma) {
+        return false;
+    }
+    match last_token.as_ref() {
+        Some(t)
+            if t.token == Token::Punct(Punct::CloseBrace) && t.brace_kind == BraceKind::Block => {}
+        _ => return false,
+    }
+    let (open, outer) = match stack.split_last() {
+        Some(v) => v,
+        None => return false,
+    };
+    let in_table = match open.token {
+        Token::Punct(Punct::OpenBracket) => open.starts_array_literal,
+        // Modules are keyed by number or path.
+        Token::Punct(Punct::OpenBrace) => {
+            open.brace_kind == BraceKind::ObjectLiteral
+                && matches!(
+                    next_token.map(|v| &v.token),
+                    Some(&Token::Number(_)) | Some(&Token::String(_))
+                )
+        }
+        _ => false,
+    };
+    in_table
+        && outer
+            .iter()
+            .any(|v| v.token == Token::Punct(Punct::OpenParen))
+        && outer.iter().all(|v| {
+            matches!(
+                &v.token,
+                &Token::Punct(Punct::OpenParen) | &Token::Punct(Punct::OpenBracket)
+            )
+        })
+}
+
+/// The clause of a control statement whose body starts after `last_token`.
+fn body_clause(last_token: &Option<Tok>) -> Option<Clause> {
+    let t = last_token.as_ref()?;
+    match &t.token {
+        &Token::Punct(Punct::CloseParen) => match t.clause {
+            Some(Clause::DoWhile) => None,
+            clause => clause,
+        },
+        &Token::Keyword(Keyword::Do(_)) => Some(Clause::Do),
+        &Token::Keyword(Keyword::Else(_)) | &Token::Keyword(Keyword::Finally(_)) => {
+            Some(Clause::Last)
+        }
+        &Token::Keyword(Keyword::Try(_)) => Some(Clause::Try),
+        _ => None,
+    }
+}
+
+/// If `token` starts the body of an `if`, `else`, loop or `with` that has no
+/// braces, returns its clause.
+fn braceless_body(token: &Tok, last_token: &Option<Tok>) -> Option<Clause> {
+    match &token.token {
+        &Token::Punct(Punct::OpenBrace) | &Token::Punct(Punct::SemiColon) => return None,
+        // `else if` is laid out as if it was a single keyword.
+        &Token::Keyword(Keyword::If(_)) => {
+            if let Some(&Token::Keyword(Keyword::Else(_))) = last_token.as_ref().map(|v| &v.token) {
+                return None;
+            }
+        }
+        _ => (),
+    }
+    match body_clause(last_token) {
+        Some(Clause::Try) | Some(Clause::Catch) => None,
+        clause => clause,
+    }
+}
+
+fn is_line_delimiter(token: &Tok, stack: &Stack) -> bool {
+    if is_inline(stack) {
+        return false;
+    }
+    if token.starts_array_literal {
+        return true;
+    }
+    match &token.token {
+        &Token::Punct(Punct::SemiColon) | &Token::Punct(Punct::Comma) => match stack.last() {
+            Some(v) => v.token != Token::Punct(Punct::OpenParen) && !v.packed,
+            None => true,
+        },
+        &Token::Punct(Punct::OpenBrace) => true,
+        &Token::Punct(Punct::Colon) => match stack.last().map(|v| &v.token) {
+            Some(&Token::Keyword(Keyword::Case(_)))
+            | Some(&Token::Keyword(Keyword::Default(_))) => true,
+            _ => token.ends_label,
+        },
+        _ => false,
+    }
+}
+
+pub(crate) struct Writer {
+    pub(crate) buffer: String,
+    pub(crate) current: SourceCoord,
+    pub(crate) last_from: SourceCoord,
+    pub(crate) mappings: Vec<SourceMapping>,
+    pub(crate) indent: u32,
+}
+
+impl Writer {
+    pub(crate) fn new(indent: u32) -> Writer {
+        Writer {
+            buffer: String::new(),
+            current: SourceCoord {
+                line: SourceMapLine(0),
+                column: SourceMapColumn(0),
+            },
+            last_from: SourceCoord {
+                line: SourceMapLine(0),
+                column: SourceMapColumn(0),
+            },
+            mappings: Vec::new(),
+            indent,
+        }
+    }
+    pub(crate) fn write_new(&mut self, s: &str) {
+        if self.mappings.is_empty() {
+            self.mappings.push(SourceMapping {
+                from: self.current,
+                to: self.last_from,
+            });
+        }
+        self.update_current(s);
+    }
+    pub(crate) fn write(&mut self, s: &str, from: SourceCoord) {
+        self.last_from = from;
+        self.mappings.push(SourceMapping {
+            from: self.current,
+            to: self.last_from,
+        });
+        self.update_current(s);
+    }
+    /// Remove the output between `start` and `end`, offsets into `buffer` and
+    /// their coordinates. There must be no mappings in between.
+    pub(crate) fn remove(&mut self, start: (usize, SourceCoord), end: (usize, SourceCoord)) {
+        self.buffer.replace_range(start.0..end.0, "");
+        let lines = end.1.line.0 - start.1.line.0;
+        let shift = |c: &mut SourceCoord| {
+            if c.line == end.1.line {
+                c.column.0 = c.column.0 - end.1.column.0 + start.1.column.0;
+            }
+            c.line.0 -= lines;
+        };
+        for m in self.mappings.iter_mut().rev() {
+            if m.from < end.1 {
+                break;
+            }
+            shift(&mut m.from);
+        }
+        shift(&mut self.current);
+    }
+    pub(crate) fn write_indent(&mut self, level: u32) {
+        let count = level * self.indent;
+        for _ in 0..count {
+            self.buffer.push(' ');
+        }
+        self.current.column.0 += count;
+    }
+    fn update_current(&mut self, s: &str) {
+        self.buffer.push_str(s);
+        for ch in s.chars() {
+            if ch == '\n' {
+                self.current.line.0 += 1;
+                self.current.column.0 = 0;
+            } else {
+                self.current.column.0 += ch.len_utf16() as u32;
+            }
+        }
+    }
+}
+
+fn append_newline(token: &Tok, stack: &Stack, out: &mut Writer) -> bool {
+    if is_line_delimiter(token, stack) {
+        out.write_new("\n");
+        return true;
+    }
+    false
+}
+
+pub(crate) fn need_space_after(token: &Tok, last_token: &Option<Tok>) -> bool {
+    match token.typescript {
+        // `f<T>()` but `= <T>(x: T) => x`.
+        Some(TypeScript::OpenAngle) => {
+            return last_token
+                .as_ref()
+                .map(|t| match &t.token {
+                    &Token::Punct(Punct::OpenBracket) | &Token::Punct(Punct::OpenParen) => false,
+                    _ => !is_identifier_like(t) && t.typescript.is_none(),
+                })
+                .unwrap_or(false)
+        }
+        Some(TypeScript::CloseAngle) | Some(TypeScript::Optional) => return false,
+        _ => (),
+    }
+    if let Some(t) = last_token.as_ref() {
+        match t.typescript {
+            Some(TypeScript::OpenAngle) | Some(TypeScript::Optional) => return false,
+            // `Map<K, V> = x` but `f<T>(x)` and `Array<T>[]`.
+            Some(TypeScript::CloseAngle) => {
+                return !matches!(
+                    &token.token,
+                    &Token::Punct(Punct::CloseBracket)
+                        | &Token::Punct(Punct::CloseParen)
+                        | &Token::Punct(Punct::Colon)
+                        | &Token::Punct(Punct::Comma)
+                        | &Token::Punct(Punct::OpenBracket)
+                        | &Token::Punct(Punct::OpenParen)
+                        | &Token::Punct(Punct::Period)
+                        | &Token::Punct(Punct::SemiColon)
+                )
+            }
+            _ => (),
+        }
+    }
+    if let Some(t) = last_token.as_ref() {
+        match &t.token {
+            // `function* g() {}`, `yield* g()` and `*m() {}`.
+            &Token::Keyword(Keyword::Function(_)) | &Token::Keyword(Keyword::Yield(_))
+                if token.token == Token::Punct(Punct::Asterisk) =>
+            {
+                return false
+            }
+            &Token::Punct(Punct::Asterisk) if t.generator_method => return false,
+            &Token::Keyword(Keyword::Do(_))
+            | &Token::Keyword(Keyword::For(_))
+            | &Token::Keyword(Keyword::While(_))
+            | &Token::Punct(Punct::Ampersand)
+            | &Token::Punct(Punct::AmpersandEqual)
+            | &Token::Punct(Punct::Asterisk)
+            | &Token::Punct(Punct::AsteriskEqual)
+            | &Token::Punct(Punct::BangDoubleEqual)
+            | &Token::Punct(Punct::BangEqual)
+            | &Token::Punct(Punct::Caret)
+            | &Token::Punct(Punct::CaretEqual)
+            | &Token::Punct(Punct::Colon)
+            | &Token::Punct(Punct::Comma)
+            | &Token::Punct(Punct::Dash)
+            | &Token::Punct(Punct::DashEqual)
+            | &Token::Punct(Punct::DoubleAmpersand)
+            | &Token::Punct(Punct::DoubleAsterisk)
+            | &Token::Punct(Punct::DoubleAsteriskEqual)
+            | &Token::Punct(Punct::DoubleEqual)
+            | &Token::Punct(Punct::DoubleGreaterThan)
+            | &Token::Punct(Punct::DoubleGreaterThanEqual)
+            | &Token::Punct(Punct::DoubleLessThan)
+            | &Token::Punct(Punct::DoubleLessThanEqual)
+            | &Token::Punct(Punct::DoublePipe)
+            | &Token::Punct(Punct::Equal)
+            | &Token::Punct(Punct::EqualGreaterThan)
+            | &Token::Punct(Punct::ForwardSlash)
+            | &Token::Punct(Punct::ForwardSlashEqual)
+            | &Token::Punct(Punct::GreaterThan)
+            | &Token::Punct(Punct::GreaterThanEqual)
+            | &Token::Punct(Punct::LessThan)
+            | &Token::Punct(Punct::LessThanEqual)
+            | &Token::Punct(Punct::Percent)
+            | &Token::Punct(Punct::PercentEqual)
+            | &Token::Punct(Punct::Pipe)
+            | &Token::Punct(Punct::PipeEqual)
+            | &Token::Punct(Punct::Plus)
+            | &Token::Punct(Punct::PlusEqual)
+            | &Token::Punct(Punct::QuestionMark)
+            | &Token::Punct(Punct::SemiColon)
+            | &Token::Punct(Punct::TripleEqual)
+            | &Token::Punct(Punct::TripleGreaterThan)
+            | &Token::Punct(Punct::TripleGreaterThanEqual) => return true,
+            &Token::Number(_) => {
+                if let &Token::Punct(Punct::Period) = &token.token {
+                    return true;
+                }
+            }
+            &Token::Keyword(Keyword::Break(_))
+            | &Token::Keyword(Keyword::Continue(_))
+            | &Token::Keyword(Keyword::Return(_))
+            | &Token::Keyword(Keyword::Yield(_)) => match &token.token {
+                &Token::Punct(Punct::Period)
+                | &Token::Punct(Punct::SemiColon)
+                | &Token::Punct(Punct::Comma)
+                | &Token::Punct(Punct::Colon)
+                | &Token::Punct(Punct::CloseParen)
+                | &Token::Punct(Punct::CloseBracket) => (),
+                _ => return true,
+            },
+            &Token::Keyword(Keyword::Debugger(_))
+            | &Token::Keyword(Keyword::Super(_))
+            | &Token::Keyword(Keyword::This(_)) => (),
+            // `import("x")` and `import.meta`.
+            &Token::Keyword(Keyword::Import(_)) => match &token.token {
+                &Token::Punct(Punct::OpenParen) | &Token::Punct(Punct::Period) => (),
+                _ => return true,
+            },
+            // `{class: 1}` is an object key, not a keyword.
+            // `as const;`.
+            &Token::Keyword(_) => match &token.token {
+                &Token::Punct(Punct::Period)
+                | &Token::Punct(Punct::Colon)
+                | &Token::Punct(Punct::SemiColon)
+                | &Token::Punct(Punct::Comma)
+                | &Token::Punct(Punct::CloseParen)
+                | &Token::Punct(Punct::CloseBracket) => (),
+                _ => return true,
+            },
+            // `f(): {a: T} {}` and `declare module "m" {}` in TypeScript.
+            &Token::Punct(Punct::CloseBrace) | &Token::String(_)
+                if token.token == Token::Punct(Punct::OpenBrace) =>
+            {
+                return true
+            }
+            &Token::Punct(Punct::CloseBrace) | &Token::Punct(Punct::CloseBracket)
+                if is_identifier_like(token) =>
+            {
+                return true
+            }
+            &Token::Punct(Punct::CloseParen) => match &token.token {
+                &Token::Punct(Punct::CloseParen)
+                | &Token::Punct(Punct::CloseBracket)
+                | &Token::Punct(Punct::Colon)
+                | &Token::Punct(Punct::SemiColon)
+                | &Token::Punct(Punct::Comma)
+                | &Token::Punct(Punct::Period) => (),
+                // Calls and indexing, e.g. `(function () {})()`.
+                &Token::Punct(Punct::OpenParen) | &Token::Punct(Punct::OpenBracket)
+                    if t.clause.is_none() => {}
+                _ => return true,
+            },
+            &Token::Ident(_) => match &token.token {
+                // `from "x"`.
+                &Token::Punct(Punct::OpenBrace) | &Token::String(_) => return true,
+                &Token::Punct(Punct::OpenParen) if token.async_arrow => return true,
+                // `for (x of [...])`.
+                &Token::Punct(Punct::OpenBracket) if t.token.matches_ident_str("of") => {
+                    return true
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+        if is_identifier_like(token) && is_identifier_like(t) {
+            return true;
+        }
+    }
+
+    match &token.token {
+        &Token::Punct(Punct::AmpersandEqual)
+        | &Token::Punct(Punct::AsteriskEqual)
+        | &Token::Punct(Punct::CaretEqual)
+        | &Token::Punct(Punct::DashEqual)
+        | &Token::Punct(Punct::DoubleAsteriskEqual)
+        | &Token::Punct(Punct::Equal)
+        | &Token::Punct(Punct::EqualGreaterThan)
+        | &Token::Punct(Punct::ForwardSlashEqual)
+        | &Token::Punct(Punct::PercentEqual)
+        | &Token::Punct(Punct::PipeEqual)
+        | &Token::Punct(Punct::PlusEqual)
+        | &Token::Punct(Punct::QuestionMark) => true,
+        &Token::Punct(Punct::Ampersand)
+        | &Token::Punct(Punct::Asterisk)
+        | &Token::Punct(Punct::BangDoubleEqual)
+        | &Token::Punct(Punct::BangEqual)
+        | &Token::Punct(Punct::Caret)
+        | &Token::Punct(Punct::Dash)
+        | &Token::Punct(Punct::DoubleAmpersand)
+        | &Token::Punct(Punct::DoubleAsterisk)
+        | &Token::Punct(Punct::DoubleEqual)
+        | &Token::Punct(Punct::DoubleGreaterThan)
+        | &Token::Punct(Punct::DoubleGreaterThanEqual)
+        | &Token::Punct(Punct::DoubleLessThan)
+        | &Token::Punct(Punct::DoubleLessThanEqual)
+        | &Token::Punct(Punct::DoublePipe)
+        | &Token::Punct(Punct::ForwardSlash)
+        | &Token::Punct(Punct::GreaterThan)
+        | &Token::Punct(Punct::GreaterThanEqual)
+        | &Token::Punct(Punct::LessThan)
+        | &Token::Punct(Punct::LessThanEqual)
+        | &Token::Punct(Punct::Percent)
+        | &Token::Punct(Punct::Pipe)
+        | &Token::Punct(Punct::Plus)
+        | &Token::Punct(Punct::TripleEqual)
+        | &Token::Punct(Punct::TripleGreaterThan)
+        | &Token::Punct(Punct::TripleGreaterThanEqual) => last_token.is_some(),
+        _ => false,
+    }
+}
+
+fn increments_indent(token: &Tok) -> bool {
+    match &token.token {
+        &Token::Punct(Punct::OpenBrace) | &Token::Keyword(Keyword::Switch(_)) => true,
+        _ => token.starts_array_literal,
+    }
+}
+
+fn decrements_indent(token: &Tok, stack: &Stack) -> bool {
+    match token.token {
+        Token::Punct(Punct::CloseBrace) => true,
+        Token::Punct(Punct::CloseBracket) => stack
+            .last()
+            .map(|v| v.starts_array_literal)
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn prepend_white_space(
+    token: &Tok,
+    last_token: &Option<Tok>,
+    stack: &Stack,
+    mut added_newline: bool,
+    mut added_space: bool,
+    indent_level: u32,
+    out: &mut Writer,
+) {
+    let after_block = last_token
+        .as_ref()
+        .map(|v| v.token == Token::Punct(Punct::CloseBrace) && !is_object_brace(v))
+        .unwrap_or(false);
+    // Set when `token` continues the statement of the block before it, e.g.
+    // `} else`, even if it is on a later line.
+    let mut continues_block = false;
+    if after_block && !is_inline(stack) {
+        match &token.token {
+            &Token::Keyword(Keyword::While(_)) if !added_newline => {
+                if let Some(&Token::Keyword(Keyword::Do(_))) = top_token(stack) {
+                    out.write_new(" ");
+                    added_space = true;
+                    continues_block = true;
+                } else {
+                    out.write_new("\n");
+                    added_newline = true;
+                }
+            }
+            &Token::Keyword(Keyword::Else(_))
+            | &Token::Keyword(Keyword::Catch(_))
+            | &Token::Keyword(Keyword::Finally(_))
+                if !added_newline =>
+            {
+                out.write_new(" ");
+                added_space = true;
+                continues_block = true;
+            }
+            &Token::Punct(Punct::OpenParen)
+            | &Token::Punct(Punct::SemiColon)
+            | &Token::Punct(Punct::Comma)
+            | &Token::Punct(Punct::CloseParen)
+            | &Token::Punct(Punct::Period)
+            | &Token::Template(_) => (),
+            // The end of a function in the middle of a conditional expression.
+            &Token::Punct(Punct::Colon)
+                if top_token(stack) == Some(&Token::Punct(Punct::QuestionMark)) => {}
+            _ => {
+                if !added_newline {
+                    out.write_new("\n");
+                    added_newline = true;
+                }
+            }
+        }
+    }
+
+    match token.token {
+        Token::Punct(Punct::Colon) if !added_newline => {
+            if let Some(&Token::Punct(Punct::QuestionMark)) = top_token(stack) {
+                out.write_new(" ");
+                added_space = true;
+            }
+        }
+        Token::Keyword(Keyword::Else(_)) => match last_token.as_ref().map(|v| &v.token) {
+            Some(&Token::Punct(Punct::CloseBrace)) | Some(&Token::Punct(Punct::Period)) => (),
+            Some(_) if !added_newline && !is_asi(token, last_token) => {
+                out.write_new(" ");
+                added_space = true;
+            }
+            _ => (),
+        },
+        _ => (),
+    }
+
+    if (is_asi(token, last_token) || decrements_indent(token, stack))
+        && !added_newline
+        && !continues_block
+        && !is_inline(stack)
+    {
+        out.write_new("\n");
+        added_newline = true;
+    }
+
+    if added_newline {
+        match &token.token {
+            &Token::Keyword(Keyword::Case(_)) | &Token::Keyword(Keyword::Default(_)) => {
+                out.write_indent(max(1, indent_level) - 1);
+            }
+            _ => {
+                out.write_indent(indent_level);
+            }
+        }
+    } else if !added_space && need_space_after(token, last_token) {
+        out.write_new(" ");
+    }
+}
+
+fn belongs_on_stack(token: &Tok, last_token: &Option<Tok>) -> bool {
+    match &token.token {
+        // `export default` is not a `case` label.
+        &Token::Keyword(Keyword::Default(_)) => !matches!(
+            last_token.as_ref().map(|v| &v.token),
+            Some(&Token::Keyword(Keyword::Export(_)))
+        ),
+        &Token::Keyword(Keyword::Case(_))
+        | &Token::Keyword(Keyword::Do(_))
+        | &Token::Keyword(Keyword::Switch(_))
+        | &Token::Punct(Punct::OpenBrace)
+        | &Token::Punct(Punct::OpenParen)
+        | &Token::Punct(Punct::OpenBracket) => true,
+        &Token::Punct(Punct::LessThan) => token.typescript.is_some(),
+        &Token::Punct(Punct::QuestionMark) => token.typescript.is_none(),
+        _ => false,
+    }
+}
+
+fn should_pop_stack(token: &Tok, stack: &Stack) -> bool {
+    match &token.token {
+        &Token::Keyword(Keyword::While(_)) => {
+            matches!(top_token(stack), Some(&Token::Keyword(Keyword::Do(_))))
+        }
+        &Token::Punct(Punct::CloseBracket)
+        | &Token::Punct(Punct::CloseParen)
+        | &Token::Punct(Punct::CloseBrace) => true,
+        &Token::Punct(Punct::GreaterThan) => token.typescript.is_some(),
+        &Token::Punct(Punct::Colon) => matches!(
+            top_token(stack),
+            Some(&Token::Keyword(Keyword::Case(_)))
+                | Some(&Token::Keyword(Keyword::Default(_)))
+                | Some(&Token::Punct(Punct::QuestionMark))
+        ),
+        _ => false,
+    }
+}
+
+fn add_comment(token: &Tok, next_token: Option<&Tok>, indent_level: u32, out: &mut Writer) -> bool {
+    out.write_indent(indent_level);
+    let comment = if let Token::Comment(c) = &token.token {
+        c
+    } else {
+        panic!("Must be a comment");
+    };
+    let need_new_line = match comment.kind {
+        CommentKind::Multi | CommentKind::Html => {
+            out.write_new("/*");
+            let mut first = true;
+            for line in comment.content.lines() {
+                if first {
+                    first = false;
+                } else {
+                    out.write_indent(indent_level);
+                    out.write_new("  ");
+                }
+                out.write_new(line);
+            }
+            out.write_indent(indent_level);
+            out.write_new("*/");
+            next_token
+                .map(|t| t.start.line != token.start.line)
+                .unwrap_or(false)
+        }
+        CommentKind::Hashbang | CommentKind::Single => {
+            out.write_new(&comment.to_string());
+            true
+        }
+    };
+    if need_new_line {
+        out.write_new("\n");
+    } else {
+        out.write_new(" ");
+    }
+    need_new_line
+}
+
+fn convert_position(pos: Position) -> SourceCoord {
+    SourceCoord {
+        line: SourceMapLine(pos.line as u32 - 1),
+        column: SourceMapColumn(pos.column as u32 - 1),
+    }
+}
+
+pub(crate) fn convert_token<'a>(item: Item<&'a str>) -> Tok<'a> {
+    debug!("token: {:?} -> {:?}", item.location.start, item.token);
+    Tok {
+        token: item.token,
+        start: convert_position(item.location.start),
+        end: convert_position(item.location.end),
+        starts_array_literal: false,
+        replaced: None,
+        brace_kind: BraceKind::Block,
+        inline: false,
+        ends_ternary_test: false,
+        ends_case: false,
+        starts_statement: false,
+        ends_label: false,
+        expression_body: false,
+        packed: false,
+        clause: None,
+        postfix: false,
+        generator_method: false,
+        async_arrow: false,
+        jsx: None,
+        typescript: None,
+    }
+}
+
+/// A `}` inserted by the prettyprinter just before `pos`.
+fn synthetic_close_brace<'a>(pos: SourceCoord) -> Tok<'a> {
+    Tok {
+        token: Token::Punct(Punct::CloseBrace),
+        start: pos,
+        end: pos,
+        starts_array_literal: false,
+        replaced: None,
+        brace_kind: BraceKind::Block,
+        inline: false,
+        ends_ternary_test: false,
+        ends_case: false,
+        starts_statement: false,
+        ends_label: false,
+        expression_body: false,
+        packed: false,
+        clause: None,
+        postfix: false,
+        generator_method: false,
+        async_arrow: false,
+        jsx: None,
+        typescript: None,
+    }
+}
+
+/// RESS 0.11 predates some ES2020+ operators and tokenizes them as several
+/// punctuators, e.g. `??` as two `?` tokens. This joins adjacent punctuators
+/// back into a single token, whose `token` is an older operator that formats
+/// identically:
+/// * `?.` formats like `.`
+/// * `??` formats like `||`
+/// * `??=`, `||=` and `&&=` format like `|=`
+struct JoinOperators<'a, I: Iterator<Item = Tok<'a>>> {
+    tokens: std::iter::Peekable<I>,
+}
+
+impl<'a, I: Iterator<Item = Tok<'a>>> JoinOperators<'a, I> {
+    fn next_if_adjacent(&mut self, token: &Tok<'a>, punct: Punct) -> Option<Tok<'a>> {
+        let end = token.end;
+        self.tokens
+            .next_if(|t| t.start == end && t.token == Token::Punct(punct))
+    }
+}
+
+impl<'a, I: Iterator<Item = Tok<'a>>> Iterator for JoinOperators<'a, I> {
+    type Item = Tok<'a>;
+    fn next(&mut self) -> Option<Tok<'a>> {
+        let mut token = self.tokens.next()?;
+        let (joined, punct) = match &token.token {
+            &Token::Punct(Punct::QuestionMark) => {
+                if let Some(t) = self.next_if_adjacent(&token, Punct::Period) {
+                    token.end = t.end;
+                    ("?.", Punct::Period)
+                } else if let Some(t) = self.next_if_adjacent(&token, Punct::QuestionMark) {
+                    token.end = t.end;
+                    if let Some(t) = self.next_if_adjacent(&token, Punct::Equal) {
+                        token.end = t.end;
+                        ("??=", Punct::PipeEqual)
+                    } else {
+                        ("??", Punct::DoublePipe)
+                    }
+                } else {
+                    return Some(token);
+                }
+            }
+            &Token::Punct(Punct::DoublePipe) | &Token::Punct(Punct::DoubleAmpersand) => {
+                if let Some(t) = self.next_if_adjacent(&token, Punct::Equal) {
+                    token.end = t.end;
+                    if token.token == Token::Punct(Punct::DoublePipe) {
+                        ("||=", Punct::PipeEqual)
+                    } else {
+                        ("&&=", Punct::PipeEqual)
+                    }
+                } else {
+                    return Some(token);
+                }
+            }
+            _ => return Some(token),
+        };
+        token.token = Token::Punct(punct);
+        token.replaced = Some(joined.to_string());
+        Some(token)
+    }
+}
+
+/// The next token that is not a comment.
+fn next_code_token<'a, I>(tokens: &mut Lookahead<I>) -> Option<Tok<'a>>
+where
+    I: Iterator<Item = Tok<'a>>,
+{
+    let mut i = 0;
+    while let Some(t) = tokens.peek_nth(i) {
+        match t.token {
+            Token::Comment(_) => i += 1,
+            Token::EoF => return None,
+            _ => return Some(t.clone()),
+        }
+    }
+    None
+}
+
+/// True if the next token that is not a comment is the keyword `keyword`.
+fn next_code_token_is<'a, I>(tokens: &mut Lookahead<I>, keyword: &str) -> bool
+where
+    I: Iterator<Item = Tok<'a>>,
+{
+    match next_code_token(tokens) {
+        Some(Tok {
+            token: Token::Keyword(ref k),
+            ..
+        }) => k.as_str() == keyword,
+        _ => false,
+    }
+}
+
+/// The next `n` tokens that are not comments, or fewer at the end of the input.
+fn next_code_tokens<'a, I>(tokens: &mut Lookahead<I>, n: usize) -> Vec<Tok<'a>>
+where
+    I: Iterator<Item = Tok<'a>>,
+{
+    let mut result = Vec::with_capacity(n);
+    let mut i = 0;
+    while let Some(t) = tokens.peek_nth(i) {
+        match t.token {
+            Token::Comment(_) => (),
+            Token::EoF => break,
+            _ => result.push(t.clone()),
+        }
+        if result.len() == n {
+            break;
+        }
+        i += 1;
+    }
+    result
+}
+
+/// An iterator adaptor that can look arbitrarily far ahead.
+pub(crate) struct Lookahead<I: Iterator> {
+    iter: I,
+    buffer: VecDeque<I::Item>,
+}
+
+impl<I: Iterator> Lookahead<I> {
+    pub(crate) fn new(iter: I) -> Lookahead<I> {
+        Lookahead {
+            iter,
+            buffer: VecDeque::new(),
+        }
+    }
+    pub(crate) fn peek_nth(&mut self, n: usize) -> Option<&I::Item> {
+        while self.buffer.len() <= n {
+            self.buffer.push_back(self.iter.next()?);
+        }
+        self.buffer.get(n)
+    }
+    pub(crate) fn peek(&mut self) -> Option<&I::Item> {
+        self.peek_nth(0)
+    }
+}
+
+impl<I: Iterator> Iterator for Lookahead<I> {
+    type Item = I::Item;
+    fn next(&mut self) -> Option<I::Item> {
+        self.buffer.pop_front().or_else(|| self.iter.next())
+    }
+}
+
+/// Options controlling `prettyprint_with_options`.
+#[derive(Clone, Debug)]
+pub struct PrettyprintOptions {
+    /// Maximum number of threads to use. When this is greater than 1, large
+    /// sources are split at top-level statement boundaries and the pieces are
+    /// prettyprinted in parallel. The result is identical to prettyprinting
+    /// on a single thread. Defaults to 1.
+    pub threads: usize,
+    /// Minimum size in bytes of each piece handed to a thread. Defaults to 1MB.
+    pub min_chunk_size: usize,
+    /// Object literals and destructuring patterns whose contents are at most
+    /// this many characters wide are kept on one line. Defaults to None, which
+    /// puts every property on its own line.
+    pub inline_object_width: Option<usize>,
+    /// `import` and `export` specifier lists such as `{a as b, c}` whose
+    /// contents are at most this many characters wide are kept on one line.
+    /// Defaults to 60. None puts every specifier on its own line.
+    pub specifier_list_width: Option<usize>,
+    /// Array literals whose elements are all primitive literals (numbers,
+    /// strings, booleans and `null`) are kept on one line if their contents
+    /// are at most this many characters wide, and otherwise packed into lines
+    /// of at most this many characters. Defaults to None, which puts every
+    /// element on its own line.
+    pub pack_array_width: Option<usize>,
+    /// Method chains such as `a(b).c(d).e(f)`, with at least two calls after the
+    /// first, that would extend beyond this many columns are broken before each
+    /// `.` that follows a call, with one extra level of indentation. Defaults to
+    /// None, which never breaks chains.
+    pub method_chain_width: Option<usize>,
+    /// Put each item of a comma sequence or declaration list in statement
+    /// position, e.g. `a(), b = c` or `var a = 1, b = 2`, on its own line with
+    /// one extra level of indentation. Defaults to false, which puts each item
+    /// on its own line without extra indentation.
+    pub split_sequences: bool,
+    /// Expressions in statement position that use `&&`, `||`, `??` or `?:`, such
+    /// as the `a && b()` that minifiers produce for `if (a) b()`, and that would
+    /// extend beyond this many columns are broken before each of those operators,
+    /// with one extra level of indentation. Defaults to None, which never breaks
+    /// them.
+    pub short_circuit_width: Option<usize>,
+    /// Insert a `;` wherever automatic semicolon insertion ends a statement, so
+    /// that every statement visibly ends. Inserted semicolons have no mapping of
+    /// their own. Defaults to false.
+    pub insert_semicolons: bool,
+    /// How to lay out the bodies of `if`, `else`, `for`, `while`, `do` and
+    /// `with` statements that are a single statement without braces.
+    pub braceless_bodies: BracelessBodies,
+    /// Insert a blank line before and after each top-level function or class
+    /// declaration, between the functions in a bundle's table of modules, and
+    /// after `"use strict"` directives. Blank lines have no mappings, so the
+    /// mappings of the code are unaffected apart from line numbers. Defaults to
+    /// false.
+    pub insert_blank_lines: bool,
+    /// Keep up to this many consecutive blank lines between statements from the
+    /// original source, for sources that are only partly minified. Defaults to
+    /// 0.
+    pub preserve_blank_lines: usize,
+    /// Recognize JSX elements, as found in React code compiled in development
+    /// mode, and lay out elements with child elements over several lines, one
+    /// child per line. Such sources are always prettyprinted on a single
+    /// thread. Defaults to false.
+    pub jsx: bool,
+    /// Tolerate TypeScript syntax, as found in scripts run by Deno or ts-node:
+    /// lay out type annotations, type parameters and arguments, and `interface`
+    /// and `type` declarations, rather than taking the `<` and `>` of generics
+    /// for comparisons and the `:` of annotations for labels. Defaults to
+    /// false.
+    pub typescript: bool,
+}
+
+impl Default for PrettyprintOptions {
+    fn default() -> PrettyprintOptions {
+        PrettyprintOptions {
+            threads: 1,
+            min_chunk_size: 1 << 20,
+            inline_object_width: None,
+            specifier_list_width: Some(60),
+            pack_array_width: None,
+            method_chain_width: None,
+            split_sequences: false,
+            short_circuit_width: None,
+            insert_semicolons: false,
+            braceless_bodies: BracelessBodies::Inline,
+            insert_blank_lines: false,
+            preserve_blank_lines: 0,
+            jsx: false,
+            typescript: false,
+        }
+    }
+}
+
+/// See `PrettyprintOptions::braceless_bodies`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BracelessBodies {
+    /// On the same line as the head of the statement, e.g. `if (a) b()`.
+    Inline,
+    /// On their own line, indented.
+    Indent,
+    /// On their own line, indented and surrounded by braces that are not
+    /// mapped to the original source.
+    Braces,
+}
+
+/// A braceless body being laid out on its own lines.
+struct Body {
+    /// Stack depth of the body's statement.
+    depth: usize,
+    clause: Clause,
+    /// The start and end of the `{` inserted before the body in the output, if
+    /// any, as offsets into the output and coordinates.
+    brace: Option<((usize, SourceCoord), (usize, SourceCoord))>,
+}
+
+pub(crate) struct Formatter<'a> {
+    options: PrettyprintOptions,
+    pub(crate) out: Writer,
+    pub(crate) indent_level: u32,
+    pub(crate) added_newline: bool,
+    pub(crate) added_space: bool,
+    pub(crate) stack: Stack<'a>,
+    pub(crate) last_token: Option<Tok<'a>>,
+    token_count: usize,
+    /// Stack depth of a `class` keyword whose body has not started yet.
+    class_depth: Option<usize>,
+    /// Set after the end of a class member, so that the next member is
+    /// preceded by a blank line.
+    class_member_ended: bool,
+    /// Stack depths of the method chains being broken over several lines.
+    chains: Vec<usize>,
+    /// Stack depths of the comma sequences and declaration lists being split
+    /// with extra indentation.
+    sequences: Vec<usize>,
+    /// Stack depths of the short-circuit expressions being broken before each
+    /// operator.
+    short_circuits: Vec<usize>,
+    /// Stack depths at which the body of a function, class or arrow function
+    /// is expected, and whether it is an expression.
+    function_bodies: Vec<(usize, bool)>,
+    /// The length of the output just after `last_token` was written.
+    pub(crate) last_token_end: usize,
+    /// The braceless bodies being laid out on their own lines.
+    bodies: Vec<Body>,
+    /// Set when a braceless body was started at a comment before its first
+    /// token.
+    body_started: bool,
+    /// Stack depths of the blocks after `case` labels.
+    case_blocks: Vec<usize>,
+    /// Set while laying out a top-level function or class declaration.
+    declaration: bool,
+    /// Stack depth at which the next statement or element is preceded by a
+    /// blank line.
+    pub(crate) blank_line: Option<usize>,
+    /// Set when the tokens continue a source whose earlier statements are
+    /// formatted elsewhere, so that a declaration at the start is preceded by a
+    /// blank line.
+    pub(crate) continuation: bool,
+    /// The line in the original source on which the last token or comment
+    /// ends.
+    pub(crate) last_line: Option<u32>,
+    /// Set between an `import` and its module specifier, where commas separate
+    /// bindings rather than expressions.
+    in_import: bool,
+    /// For each JSX element being laid out, innermost last, whether its
+    /// children go on their own lines.
+    pub(crate) jsx_elements: Vec<bool>,
+}
+
+impl<'a> Formatter<'a> {
+    pub(crate) fn new(options: &PrettyprintOptions) -> Formatter<'a> {
+        Formatter {
+            options: options.clone(),
+            out: Writer::new(2),
+            indent_level: 0,
+            added_newline: false,
+            added_space: false,
+            stack: Vec::new(),
+            last_token: None,
+            token_count: 0,
+            class_depth: None,
+            class_member_ended: false,
+            chains: Vec::new(),
+            sequences: Vec::new(),
+            short_circuits: Vec::new(),
+            function_bodies: Vec::new(),
+            last_token_end: 0,
+            bodies: Vec::new(),
+            body_started: false,
+            case_blocks: Vec::new(),
+            declaration: false,
+            blank_line: None,
+            continuation: false,
+            last_line: None,
+            in_import: false,
+            jsx_elements: Vec::new(),
+        }
+    }
+
+    pub(crate) fn format<I>(&mut self, tokens: I)
+    where
+        I: Iterator<Item = Tok<'a>>,
+    {
+        // An unlimited budget is never exceeded.
+        let _ = self.format_with_budget(tokens, &Budget::default());
+    }
+
+    pub(crate) fn format_with_budget<I>(
+        &mut self,
+        tokens: I,
+        budget: &Budget,
+    ) -> Result<(), BudgetExceeded>
+    where
+        I: Iterator<Item = Tok<'a>>,
+    {
+        let mut scanner = Lookahead::new(JoinOperators {
+            tokens: tokens.peekable(),
+        });
+        while let Some(mut token) = scanner.next() {
+            if let &Token::EoF = &token.token {
+                break;
+            }
+            if let Some(limit) = budget.check(self.token_count, self.out.buffer.len()) {
+                return Err(BudgetExceeded {
+                    limit,
+                    tokens: self.token_count,
+                    output_size: self.out.buffer.len(),
+                    position: Some(token.start),
+                });
+            }
+            self.token_count += 1;
+
+            let next_token = scanner.peek().cloned();
+            let next_token = next_token.as_ref();
+            if let &Token::Comment(_) = &token.token {
+                // A body's braces and indentation go before its comments.
+                let mut starts_body = false;
+                if self.options.braceless_bodies != BracelessBodies::Inline && !self.body_started {
+                    let clause = next_code_token(&mut scanner)
+                        .and_then(|next| braceless_body(&next, &self.last_token));
+                    if let Some(clause) = clause {
+                        self.start_body(clause);
+                        self.body_started = true;
+                        starts_body = true;
+                    }
+                }
+                let comment_indent_level = if starts_body {
+                    self.out.write_indent(self.indent_level);
+                    0
+                } else if self
+                    .last_token
+                    .as_ref()
+                    .map(|v| v.end.line == token.start.line)
+                    .unwrap_or(false)
+                {
+                    self.out.write_new(" ");
+                    0
+                } else {
+                    let after_brace = self
+                        .last_token
+                        .as_ref()
+                        .map(|v| v.token == Token::Punct(Punct::CloseBrace))
+                        .unwrap_or(false);
+                    if !self.added_newline && (after_brace || self.class_member_ended) {
+                        self.out.write_new("\n");
+                        self.added_newline = true;
+                    }
+                    if self.class_member_ended {
+                        self.out.write_new("\n");
+                        self.class_member_ended = false;
+                    }
+                    if self.options.insert_blank_lines {
+                        // A comment before a declaration goes after the blank line.
+                        let next = next_code_tokens(&mut scanner, 4);
+                        if self.start_declaration(&next)
+                            || self.blank_line == Some(self.stack.len())
+                        {
+                            self.write_blank_line();
+                        }
+                    }
+                    self.preserve_blank_lines(&token);
+                    self.indent_level
+                };
+                self.last_line = Some(token.end.line.0);
+                self.added_newline =
+                    add_comment(&token, next_token, comment_indent_level, &mut self.out);
+                self.added_space = !self.added_newline;
+                continue;
+            }
+            if let Some(jsx) = token.jsx {
+                self.format_jsx(token, jsx, &mut scanner);
+                continue;
+            }
+
+            // `a.catch(...)`, `e.default` etc. are property names, not keywords.
+            // So are `case` and `default` outside a `switch`, e.g. `{default: 1}`,
+            // except in `export default`.
+            if let Token::Keyword(k) = &token.token {
+                let property = match (&token.token, self.last_token.as_ref().map(|v| &v.token)) {
+                    (_, Some(&Token::Punct(Punct::Period))) => true,
+                    (
+                        &Token::Keyword(Keyword::Default(_)),
+                        Some(&Token::Keyword(Keyword::Export(_))),
+                    ) => false,
+                    (&Token::Keyword(Keyword::Case(_)), _)
+                    | (&Token::Keyword(Keyword::Default(_)), _) => !in_switch_body(&self.stack),
+                    _ => false,
+                };
+                if property {
+                    token.replaced = Some(k.to_string());
+                    token.token = Token::Ident(Ident::from(""));
+                }
+            }
+
+            token.starts_array_literal =
+                starts_array_literal(&token, &self.last_token, &self.stack);
+            if token.starts_array_literal && !is_inline(&self.stack) {
+                if let Some(max_width) = self.options.pack_array_width {
+                    match scan_array(&mut scanner, true) {
+                        Some(width) if width <= max_width => token.inline = true,
+                        Some(_) => token.packed = true,
+                        None => (),
+                    }
+                }
+            }
+
+            match &token.token {
+                // Not `import("x")` or `import.meta`.
+                &Token::Keyword(Keyword::Import(_)) => {
+                    self.in_import = !matches!(
+                        next_token.map(|v| &v.token),
+                        Some(&Token::Punct(Punct::OpenParen)) | Some(&Token::Punct(Punct::Period))
+                    );
+                }
+                &Token::String(_) | &Token::Punct(Punct::SemiColon) => self.in_import = false,
+                _ => (),
+            }
+
+            let class_depth = self.class_depth == Some(self.stack.len());
+            match &token.token {
+                &Token::Keyword(Keyword::Class(_)) => {
+                    self.class_depth = Some(self.stack.len());
+                }
+                &Token::Punct(Punct::OpenBrace) if class_depth => {
+                    token.brace_kind = BraceKind::ClassBody;
+                    self.class_depth = None;
+                }
+                &Token::Punct(Punct::OpenBrace) => {
+                    let specifiers = match self.last_token.as_ref().map(|v| &v.token) {
+                        Some(&Token::Keyword(Keyword::Export(_)))
+                        | Some(&Token::Keyword(Keyword::Import(_))) => true,
+                        Some(&Token::Punct(Punct::Comma)) => self.in_import,
+                        _ => false,
+                    };
+                    // Import and export lists are laid out like object literals,
+                    // and `export default {...}` is one.
+                    token.brace_kind = match self.last_token.as_ref().map(|v| &v.token) {
+                        _ if specifiers => BraceKind::ObjectLiteral,
+                        Some(&Token::Keyword(Keyword::Default(_))) => BraceKind::ObjectLiteral,
+                        _ => brace_kind(&self.last_token, &self.stack),
+                    };
+                    if token.brace_kind == BraceKind::ObjectLiteral
+                        || token.brace_kind == BraceKind::Pattern
+                    {
+                        let max_width = if is_inline(&self.stack) {
+                            None
+                        } else if specifiers {
+                            self.options.specifier_list_width
+                        } else {
+                            self.options.inline_object_width
+                        };
+                        let (pattern, inline) = scan_object(&mut scanner, max_width);
+                        if pattern {
+                            token.brace_kind = BraceKind::Pattern;
+                        }
+                        token.inline = inline;
+                    }
+                }
+                // Not a class after all, e.g. `{class: 1}`.
+                &Token::Punct(Punct::Colon)
+                | &Token::Punct(Punct::Comma)
+                | &Token::Punct(Punct::SemiColon)
+                | &Token::Punct(Punct::Equal)
+                | &Token::Punct(Punct::CloseBrace)
+                    if class_depth =>
+                {
+                    self.class_depth = None;
+                }
+                _ => (),
+            }
+
+            // Keep empty blocks and literals on one line.
+            if let &Token::Punct(Punct::OpenBrace) = &token.token {
+                if let Some(&Token::Punct(Punct::CloseBrace)) = next_token.map(|v| &v.token) {
+                    token.inline = true;
+                }
+            }
+
+            if self.class_member_ended {
+                match token.token {
+                    Token::Punct(Punct::SemiColon) => (),
+                    Token::Punct(Punct::CloseBrace) => self.class_member_ended = false,
+                    _ => {
+                        self.out.write_new("\n");
+                        self.class_member_ended = false;
+                    }
+                }
+            }
+
+            token.starts_statement = starts_statement(&token, &self.last_token, &self.stack);
+            while self.function_bodies.last().map(|v| v.0 > self.stack.len()) == Some(true) {
+                self.function_bodies.pop();
+            }
+            if token.token == Token::Punct(Punct::OpenBrace)
+                && !is_object_brace(&token)
+                && self.function_bodies.last().map(|v| v.0) == Some(self.stack.len())
+            {
+                token.expression_body = self.function_bodies.pop().unwrap().1;
+            }
+            match token.token {
+                Token::Punct(Punct::OpenBrace) if token.brace_kind == BraceKind::Block => {
+                    token.clause = body_clause(&self.last_token);
+                }
+                Token::Punct(Punct::CloseBrace) => {
+                    if let Some(open) = self.stack.last() {
+                        token.brace_kind = open.brace_kind;
+                        token.expression_body = open.expression_body;
+                        token.clause = open.clause;
+                    }
+                }
+                // A declaration, e.g. `export default class {}` or
+                // `async function f() {}`, is not an expression.
+                Token::Keyword(Keyword::Function(_)) | Token::Keyword(Keyword::Class(_)) => {
+                    let declaration = token.starts_statement
+                        || match self.last_token.as_ref() {
+                            Some(t) => match &t.token {
+                                &Token::Keyword(Keyword::Export(_))
+                                | &Token::Keyword(Keyword::Default(_)) => true,
+                                &Token::Ident(_) => t.starts_statement,
+                                _ => false,
+                            },
+                            None => true,
+                        };
+                    self.function_bodies.push((self.stack.len(), !declaration));
+                }
+                Token::Punct(Punct::EqualGreaterThan)
+                    if next_token.map(|v| v.token == Token::Punct(Punct::OpenBrace))
+                        == Some(true) =>
+                {
+                    self.function_bodies.push((self.stack.len(), true));
+                }
+                Token::Punct(Punct::CloseParen) => {
+                    token.clause = self.stack.last().and_then(|v| v.clause);
+                }
+                Token::Punct(Punct::OpenParen) => {
+                    token.clause = self.last_token.as_ref().and_then(|t| match &t.token {
+                        &Token::Keyword(Keyword::Catch(_)) => Some(Clause::Catch),
+                        &Token::Keyword(Keyword::If(_)) => Some(Clause::If),
+                        &Token::Keyword(Keyword::For(_))
+                        | &Token::Keyword(Keyword::Switch(_))
+                        | &Token::Keyword(Keyword::With(_)) => Some(Clause::Last),
+                        &Token::Keyword(Keyword::While(_)) | &Token::Keyword(Keyword::Await(_)) => {
+                            t.clause
+                        }
+                        _ => None,
+                    });
+                    // Keep `for (...)` headers on one line, commas and all.
+                    match self.last_token.as_ref() {
+                        Some(&Tok {
+                            token: Token::Keyword(Keyword::For(_)),
+                            ..
+                        })
+                        | Some(&Tok {
+                            token: Token::Keyword(Keyword::Await(_)),
+                            clause: Some(_),
+                            ..
+                        }) => token.inline = scan_for_header(&mut scanner),
+                        Some(t) if t.token.matches_ident_str("async") => {
+                            token.async_arrow = is_arrow_params(&mut scanner);
+                        }
+                        _ => (),
+                    }
+                }
+                Token::Keyword(Keyword::While(_)) => match top_token(&self.stack) {
+                    Some(&Token::Keyword(Keyword::Do(_))) => token.clause = Some(Clause::DoWhile),
+                    _ => token.clause = Some(Clause::Last),
+                },
+                // `for await (...)`.
+                Token::Keyword(Keyword::Await(_)) => {
+                    if let Some(&Token::Keyword(Keyword::For(_))) =
+                        self.last_token.as_ref().map(|v| &v.token)
+                    {
+                        token.clause = Some(Clause::Last);
+                    }
+                }
+                // A generator method, e.g. `{*m() {}}` or `async *m() {}`.
+                Token::Punct(Punct::Asterisk)
+                    if in_class_body(&self.stack)
+                        || self.stack.last().map(is_object_brace).unwrap_or(false) =>
+                {
+                    token.generator_method = match self.last_token.as_ref().map(|v| &v.token) {
+                        Some(&Token::Punct(Punct::OpenBrace))
+                        | Some(&Token::Punct(Punct::CloseBrace))
+                        | Some(&Token::Punct(Punct::Comma))
+                        | Some(&Token::Punct(Punct::SemiColon))
+                        | Some(&Token::Keyword(Keyword::Static(_))) => true,
+                        Some(t) => t.matches_ident_str("async"),
+                        None => false,
+                    };
+                }
+                Token::Punct(Punct::Colon) => match top_token(&self.stack) {
+                    Some(&Token::Keyword(Keyword::Case(_)))
+                    | Some(&Token::Keyword(Keyword::Default(_))) => token.ends_case = true,
+                    Some(&Token::Punct(Punct::QuestionMark)) => (),
+                    // Anywhere but an object literal, a `:` that is not the label
+                    // of a loop is a type annotation.
+                    _ if self.options.typescript
+                        && !self.stack.last().map(is_object_brace).unwrap_or(false)
+                        && !(next_code_token_is(&mut scanner, "for")
+                            || next_code_token_is(&mut scanner, "while")
+                            || next_code_token_is(&mut scanner, "do")) =>
+                    {
+                        token.typescript = Some(TypeScript::TypeColon);
+                    }
+                    // A label is a single identifier at the start of a statement.
+                    _ if in_statement(&self.stack)
+                        && self
+                            .last_token
+                            .as_ref()
+                            .map(|t| t.starts_statement && matches!(t.token, Token::Ident(_)))
+                            .unwrap_or(false) =>
+                    {
+                        token.ends_label = true;
+                    }
+                    _ => (),
+                },
+                // Nested type arguments have been marked already.
+                Token::Punct(Punct::LessThan)
+                    if self.options.typescript && token.typescript.is_none() =>
+                {
+                    let after_operand = matches!(
+                        self.last_token.as_ref().map(|v| &v.token),
+                        Some(&Token::Ident(_))
+                    );
+                    if scan_type_arguments(&mut scanner, after_operand) {
+                        token.typescript = Some(TypeScript::OpenAngle);
+                        token.inline = true;
+                    }
+                }
+                // `a?: T` and `f(a?, b?)`.
+                Token::Punct(Punct::QuestionMark) if self.options.typescript => {
+                    match next_token.map(|v| &v.token) {
+                        Some(&Token::Punct(Punct::Colon))
+                        | Some(&Token::Punct(Punct::Comma))
+                        | Some(&Token::Punct(Punct::CloseParen)) => {
+                            token.typescript = Some(TypeScript::Optional);
+                        }
+                        _ => (),
+                    }
+                }
+                _ => (),
+            }
+
+            if self.options.insert_semicolons
+                && self.out.buffer.len() == self.last_token_end
+                && needs_semicolon(&token, &self.last_token, &self.stack)
+            {
+                self.out.write_new(";");
+                self.last_token_end = self.out.buffer.len();
+            }
+
+            if self.chains.last() == Some(&self.stack.len())
+                && !continues_chain(&token, &self.last_token)
+            {
+                self.chains.pop();
+                self.indent_level = max(1, self.indent_level) - 1;
+            }
+            if self.sequences.last() == Some(&self.stack.len()) {
+                match &token.token {
+                    &Token::Punct(Punct::SemiColon)
+                    | &Token::Punct(Punct::CloseBrace)
+                    | &Token::Keyword(Keyword::While(_)) => {
+                        self.sequences.pop();
+                        self.indent_level = max(1, self.indent_level) - 1;
+                    }
+                    _ if is_asi(&token, &self.last_token) => {
+                        self.sequences.pop();
+                        self.indent_level = max(1, self.indent_level) - 1;
+                    }
+                    _ => (),
+                }
+            }
+            if self.short_circuits.last() == Some(&self.stack.len()) {
+                match &token.token {
+                    &Token::Punct(Punct::SemiColon)
+                    | &Token::Punct(Punct::Comma)
+                    | &Token::Punct(Punct::CloseBrace)
+                    | &Token::Keyword(Keyword::While(_)) => {
+                        self.short_circuits.pop();
+                        self.indent_level = max(1, self.indent_level) - 1;
+                    }
+                    _ if is_asi(&token, &self.last_token) => {
+                        self.short_circuits.pop();
+                        self.indent_level = max(1, self.indent_level) - 1;
+                    }
+                    _ => (),
+                }
+            }
+            if let Some(depth) = self.short_circuit_depth(&token) {
+                if self.short_circuits.last() != Some(&depth) {
+                    if let Some(max_width) = self.options.short_circuit_width {
+                        let column = self.out.current.column.0 as usize;
+                        if scan_expression(&mut scanner, column, max_width) {
+                            self.short_circuits.push(depth);
+                            self.indent_level += 1;
+                        }
+                    }
+                }
+                if self.short_circuits.last() == Some(&depth) {
+                    self.out.write_new("\n");
+                    self.added_newline = true;
+                }
+            }
+
+            if self.options.split_sequences
+                && token.token == Token::Punct(Punct::Comma)
+                && in_statement(&self.stack)
+                && self.sequences.last() != Some(&self.stack.len())
+            {
+                self.sequences.push(self.stack.len());
+                self.indent_level += 1;
+            }
+
+            let after_call = self.last_token.as_ref().map(|v| &v.token)
+                == Some(&Token::Punct(Punct::CloseParen));
+            if token.token == Token::Punct(Punct::Period) && after_call && !is_inline(&self.stack) {
+                let depth = self.stack.len();
+                if self.chains.last() != Some(&depth) {
+                    if let Some(max_width) = self.options.method_chain_width {
+                        let column = self.out.current.column.0 as usize;
+                        if scan_chain(&mut scanner, column, max_width) {
+                            self.chains.push(depth);
+                            self.indent_level += 1;
+                        }
+                    }
+                }
+                if self.chains.last() == Some(&depth) {
+                    self.out.write_new("\n");
+                    self.added_newline = true;
+                }
+            }
+
+            if self.bodies.last().map(|v| v.depth) == Some(self.stack.len()) {
+                let can_end = match self.last_token.as_ref() {
+                    Some(t) => {
+                        t.token == Token::Punct(Punct::CloseBrace)
+                            || can_end_statement(t, &self.stack)
+                    }
+                    None => false,
+                };
+                let last_token = self.last_token.as_ref();
+                if token.token == Token::Punct(Punct::CloseBrace)
+                    || (can_end && begins_statement(&token, last_token.unwrap()))
+                {
+                    self.end_statement(Some(&token));
+                } else if can_end
+                    && token.token == Token::Punct(Punct::OpenBrace)
+                    && last_token.unwrap().end.line != token.start.line
+                {
+                    // A block after ASI, or e.g. a function body, so we can't
+                    // tell where the body ends.
+                    self.drop_braces();
+                }
+            }
+            if self.options.braceless_bodies != BracelessBodies::Inline && !self.body_started {
+                if let Some(clause) = braceless_body(&token, &self.last_token) {
+                    self.start_body(clause);
+                }
+            }
+            self.body_started = false;
+
+            // Start a new line in a packed array if the next element doesn't fit.
+            if let (Some(open), Some(last)) = (self.stack.last(), self.last_token.as_ref()) {
+                if open.packed
+                    && last.token == Token::Punct(Punct::Comma)
+                    && token.token != Token::Punct(Punct::CloseBracket)
+                {
+                    let used = self.out.current.column.0 - self.indent_level * self.out.indent;
+                    let width = element_width(&token, &mut scanner, true);
+                    if used as usize + 1 + width > self.options.pack_array_width.unwrap_or(0) {
+                        self.out.write_new("\n");
+                        self.added_newline = true;
+                    }
+                }
+            }
+
+            if self.options.insert_blank_lines {
+                let mut blank_line = false;
+                if self.stack.is_empty() && !self.declaration {
+                    let mut first = vec![token.clone()];
+                    first.extend(next_code_tokens(&mut scanner, 3));
+                    blank_line = self.start_declaration(&first);
+                }
+                if self.blank_line == Some(self.stack.len()) {
+                    match &token.token {
+                        &Token::Punct(Punct::SemiColon) | &Token::Punct(Punct::Comma) => (),
+                        &Token::Punct(Punct::CloseBrace) | &Token::Punct(Punct::CloseBracket) => {
+                            self.blank_line = None;
+                        }
+                        _ => blank_line = true,
+                    }
+                }
+                if blank_line {
+                    self.write_blank_line();
+                }
+            }
+            self.preserve_blank_lines(&token);
+
+            // The block after a `case` label goes on the label's line, with its
+            // contents at the level of any other statements after the label.
+            let after_case = self.last_token.as_ref().map(|v| v.ends_case) == Some(true);
+            if token.token == Token::Punct(Punct::OpenBrace) && after_case && !self.added_newline {
+                self.case_blocks.push(self.stack.len() + 1);
+                self.indent_level = max(1, self.indent_level) - 1;
+            }
+            let ends_case_block = token.token == Token::Punct(Punct::CloseBrace)
+                && self.case_blocks.last() == Some(&self.stack.len());
+
+            if belongs_on_stack(&token, &self.last_token) {
+                token.inline |= is_inline(&self.stack);
+                self.stack.push(token.clone());
+            }
+
+            if decrements_indent(&token, &self.stack) {
+                self.indent_level = max(1, self.indent_level) - 1;
+                if let &Token::Punct(Punct::CloseBrace) = &token.token {
+                    if self.stack.len() >= 2 {
+                        if let &Token::Keyword(Keyword::Switch(_)) =
+                            &self.stack[self.stack.len() - 2].token
+                        {
+                            self.indent_level = max(1, self.indent_level) - 1;
+                        }
+                    }
+                }
+            }
+
+            prepend_white_space(
+                &token,
+                &self.last_token,
+                &self.stack,
+                self.added_newline,
+                self.added_space,
+                self.indent_level,
+                &mut self.out,
+            );
+
+            self.out.write(&token.text(), token.start);
+            self.last_token_end = self.out.buffer.len();
+
+            self.added_space = false;
+            let mut same_line_comment = false;
+            if let Some(&Token::Comment(_)) = next_token.as_ref().map(|v| &v.token) {
+                if next_token.unwrap().start.line == token.end.line {
+                    same_line_comment = true;
+                }
+            }
+            let case_block = token.ends_case
+                && next_token.map(|v| &v.token) == Some(&Token::Punct(Punct::OpenBrace));
+            let import_comma = self.in_import
+                && token.token == Token::Punct(Punct::Comma)
+                && in_statement(&self.stack);
+            if !same_line_comment && !case_block && !import_comma {
+                self.added_newline = append_newline(&token, &self.stack, &mut self.out);
+            }
+            // What follows `do {} while (x)` goes on a new line even without a `;`.
+            if token.token == Token::Punct(Punct::CloseParen)
+                && token.clause == Some(Clause::DoWhile)
+                && !same_line_comment
+                && !self.added_newline
+                && next_token.map(|v| &v.token) != Some(&Token::Punct(Punct::SemiColon))
+            {
+                if self.options.insert_semicolons {
+                    self.out.write_new(";");
+                }
+                self.out.write_new("\n");
+                self.added_newline = true;
+            }
+
+            if should_pop_stack(&token, &self.stack) {
+                if let Some(&Token::Punct(Punct::QuestionMark)) = top_token(&self.stack) {
+                    token.ends_ternary_test = token.token == Token::Punct(Punct::Colon);
+                }
+                self.stack.pop();
+                if let &Token::Punct(Punct::CloseBrace) = &token.token {
+                    if let Some(&Token::Keyword(Keyword::Switch(_))) =
+                        self.stack.last().map(|v| &v.token)
+                    {
+                        self.stack.pop();
+                    }
+                }
+            }
+
+            if increments_indent(&token) {
+                self.indent_level += 1;
+            }
+            if ends_case_block {
+                self.case_blocks.pop();
+                self.indent_level += 1;
+            }
+
+            if in_class_body(&self.stack) {
+                match &token.token {
+                    &Token::Punct(Punct::CloseBrace) | &Token::Punct(Punct::SemiColon) => {
+                        self.class_member_ended = true;
+                    }
+                    _ => (),
+                }
+            }
+
+            let ends_statement = match token.token {
+                Token::Punct(Punct::SemiColon) => true,
+                Token::Punct(Punct::CloseBrace) => match token.clause {
+                    Some(Clause::If) => !next_code_token_is(&mut scanner, "else"),
+                    Some(Clause::Catch) => !next_code_token_is(&mut scanner, "finally"),
+                    Some(Clause::Try) | Some(Clause::Do) | None => false,
+                    Some(Clause::DoWhile) | Some(Clause::Last) => true,
+                },
+                Token::Punct(Punct::CloseParen) if token.clause == Some(Clause::DoWhile) => {
+                    next_code_token(&mut scanner).map(|v| v.token)
+                        != Some(Token::Punct(Punct::SemiColon))
+                }
+                _ => false,
+            };
+            if self.options.insert_blank_lines {
+                if self.declaration
+                    && self.stack.is_empty()
+                    && token.token == Token::Punct(Punct::CloseBrace)
+                {
+                    self.declaration = false;
+                    self.blank_line = Some(0);
+                }
+                if token.token.matches_string_content("use strict")
+                    && self.is_directive_position()
+                    && match next_token.map(|v| &v.token) {
+                        None
+                        | Some(&Token::Punct(Punct::SemiColon))
+                        | Some(&Token::Punct(Punct::CloseBrace)) => true,
+                        Some(_) => begins_statement(next_token.unwrap(), &token),
+                    }
+                {
+                    self.blank_line = Some(self.stack.len());
+                }
+                if ends_module_wrapper(&token, &self.last_token, next_token, &self.stack) {
+                    self.blank_line = Some(self.stack.len());
+                }
+            }
+            self.last_line = Some(token.end.line.0);
+            self.last_token = Some(token);
+            if ends_statement && self.bodies.last().map(|v| v.depth) == Some(self.stack.len()) {
+                let next = next_code_token(&mut scanner);
+                self.end_statement(next.as_ref());
+            }
+        }
+        Ok(())
+    }
+
+    /// If `tokens`, the first tokens of what follows, start a top-level function
+    /// or class declaration, note it and return whether it is preceded by a
+    /// blank line.
+    fn start_declaration(&mut self, tokens: &[Tok<'a>]) -> bool {
+        let first = match tokens.first() {
+            Some(t) => t,
+            None => return false,
+        };
+        if !self.stack.is_empty() || self.declaration {
+            return false;
+        }
+        let starts_statement = match self.last_token.as_ref().map(|v| &v.token) {
+            None
+            | Some(&Token::Punct(Punct::SemiColon))
+            | Some(&Token::Punct(Punct::CloseBrace)) => true,
+            Some(_) => is_asi(first, &self.last_token),
+        };
+        self.declaration = starts_statement && starts_declaration(tokens);
+        self.declaration && (self.last_token.is_some() || self.continuation)
+    }
+
+    /// True if a statement starting after `last_token` is at the start of the
+    /// source or of a block, where directives go.
+    fn is_directive_position(&self) -> bool {
+        match self.last_token.as_ref() {
+            None => !self.continuation,
+            Some(t) => {
+                t.token == Token::Punct(Punct::OpenBrace) && t.brace_kind == BraceKind::Block
+            }
         }
     }
 
-    match &token.token {
-        &Token::Punct(Punct::AmpersandEqual)
-        | &Token::Punct(Punct::AsteriskEqual)
-        | &Token::Punct(Punct::CaretEqual)
-        | &Token::Punct(Punct::DashEqual)
-        | &Token::Punct(Punct::DoubleAsteriskEqual)
-        | &Token::Punct(Punct::Equal)
-        | &Token::Punct(Punct::ForwardSlashEqual)
-        | &Token::Punct(Punct::PercentEqual)
-        | &Token::Punct(Punct::PipeEqual)
-        | &Token::Punct(Punct::PlusEqual)
-        | &Token::Punct(Punct::QuestionMark) => true,
-        &Token::Punct(Punct::Ampersand)
-        | &Token::Punct(Punct::Asterisk)
-        | &Token::Punct(Punct::BangDoubleEqual)
-        | &Token::Punct(Punct::BangEqual)
-        | &Token::Punct(Punct::Caret)
-        | &Token::Punct(Punct::Dash)
-        | &Token::Punct(Punct::DoubleAmpersand)
-        | &Token::Punct(Punct::DoubleAsterisk)
-        | &Token::Punct(Punct::DoubleEqual)
-        | &Token::Punct(Punct::DoubleGreaterThan)
-        | &Token::Punct(Punct::DoubleGreaterThanEqual)
-        | &Token::Punct(Punct::DoubleLessThan)
-        | &Token::Punct(Punct::DoubleLessThanEqual)
-        | &Token::Punct(Punct::DoublePipe)
-        | &Token::Punct(Punct::GreaterThan)
-        | &Token::Punct(Punct::GreaterThanEqual)
-        | &Token::Punct(Punct::LessThan)
-        | &Token::Punct(Punct::LessThanEqual)
-        | &Token::Punct(Punct::Percent)
-        | &Token::Punct(Punct::Pipe)
-        | &Token::Punct(Punct::Plus) => last_token.is_some(),
-        _ => false,
+    fn write_blank_line(&mut self) {
+        self.write_blank_lines(1);
+        self.blank_line = None;
     }
-}
 
-fn increments_indent(token: &Tok) -> bool {
-    match &token.token {
-        &Token::Punct(Punct::OpenBrace) | &Token::Keyword(Keyword::Switch(_)) => true,
-        _ => token.starts_array_literal,
+    /// Make sure that the next line is preceded by at least `n` blank lines.
+    fn write_blank_lines(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        if !self.added_newline && !self.out.buffer.is_empty() {
+            self.out.write_new("\n");
+        }
+        let newlines = self
+            .out
+            .buffer
+            .bytes()
+            .rev()
+            .take_while(|&b| b == b'\n')
+            .count();
+        for _ in max(1, newlines) - 1..n {
+            // Not `write_new`: a blank line only starts the output of a
+            // continuation, which must not get a mapping of its own.
+            self.out.update_current("\n");
+        }
+        self.added_newline = true;
     }
-}
 
-fn decrements_indent(token: &Tok, stack: &Stack) -> bool {
-    match &token.token {
-        &Token::Punct(Punct::CloseBrace) => true,
-        &Token::Punct(Punct::CloseBracket) => stack
-            .last()
-            .map(|v| v.starts_array_literal)
-            .unwrap_or(false),
-        _ => false,
+    /// Keep up to `preserve_blank_lines` of the blank lines before `token` in
+    /// the original source, if `token` starts a statement on a new line.
+    fn preserve_blank_lines(&mut self, token: &Tok) {
+        let line = match self.last_line {
+            Some(line) => line,
+            None => return,
+        };
+        let at_statement = match top_token(&self.stack) {
+            Some(&Token::Keyword(Keyword::Case(_)))
+            | Some(&Token::Keyword(Keyword::Default(_))) => true,
+            _ => in_statement(&self.stack),
+        };
+        let starts_line = self.added_newline
+            || is_asi(token, &self.last_token)
+            || match self.last_token.as_ref() {
+                Some(t) if t.token == Token::Punct(Punct::CloseBrace) => begins_statement(token, t),
+                _ => false,
+            };
+        let continues_statement = match &token.token {
+            &Token::Keyword(Keyword::Else(_))
+            | &Token::Keyword(Keyword::Catch(_))
+            | &Token::Keyword(Keyword::Finally(_))
+            | &Token::Punct(Punct::CloseBrace) => true,
+            &Token::Keyword(Keyword::While(_)) => matches!(
+                top_token(&self.stack),
+                Some(&Token::Keyword(Keyword::Do(_)))
+            ),
+            _ => false,
+        };
+        if at_statement && starts_line && !continues_statement {
+            let blank_lines = token.start.line.0.saturating_sub(line + 1) as usize;
+            self.write_blank_lines(min(blank_lines, self.options.preserve_blank_lines));
+        }
     }
-}
 
-fn prepend_white_space(
-    token: &Tok,
-    last_token: &Option<Tok>,
-    stack: &Stack,
-    mut added_newline: bool,
-    mut added_space: bool,
-    indent_level: u32,
-    out: &mut Writer,
-) {
-    if let Some(&Token::Punct(Punct::CloseBrace)) = last_token.as_ref().map(|v| &v.token) {
-        match &token.token {
-            &Token::Keyword(Keyword::While(_)) => {
-                if let Some(&Token::Keyword(Keyword::Do(_))) = top_token(stack) {
-                    out.write_new(" ");
-                    added_space = true;
-                } else {
-                    out.write_new("\n");
-                    added_newline = true;
-                }
+    fn start_body(&mut self, clause: Clause) {
+        let mut brace = None;
+        if self.options.braceless_bodies == BracelessBodies::Braces {
+            let start = (self.out.buffer.len(), self.out.current);
+            if self.added_newline {
+                self.out.write_indent(self.indent_level);
+                self.out.write_new("{");
+                self.out.write_new("\n");
+                brace = Some((start, (self.out.buffer.len(), self.out.current)));
+            } else {
+                self.out.write_new(" {");
+                brace = Some((start, (self.out.buffer.len(), self.out.current)));
+                self.out.write_new("\n");
             }
-            &Token::Keyword(Keyword::Else(_))
-            | &Token::Keyword(Keyword::Catch(_))
-            | &Token::Keyword(Keyword::Finally(_)) => {
-                out.write_new(" ");
-                added_space = true;
+        } else if !self.added_newline {
+            self.out.write_new("\n");
+        }
+        self.bodies.push(Body {
+            depth: self.stack.len(),
+            clause,
+            brace,
+        });
+        self.added_newline = true;
+        self.indent_level += 1;
+    }
+
+    /// Lay out the braceless bodies that would end with the current statement
+    /// without braces after all, removing the `{` inserted before them.
+    fn drop_braces(&mut self) {
+        for body in self.bodies.iter_mut().rev() {
+            if body.depth != self.stack.len() {
+                break;
             }
-            &Token::Punct(Punct::OpenParen)
-            | &Token::Punct(Punct::SemiColon)
-            | &Token::Punct(Punct::Comma)
-            | &Token::Punct(Punct::CloseParen)
-            | &Token::Punct(Punct::Period)
-            | &Token::Template(_) => (),
-            _ => {
-                out.write_new("\n");
-                added_newline = true;
+            // Later braces first, so the positions of earlier ones still hold.
+            if let Some((start, end)) = body.brace.take() {
+                self.out.remove(start, end);
+                if self.last_token_end >= end.0 {
+                    self.last_token_end -= end.0 - start.0;
+                }
             }
         }
     }
 
-    match &token.token {
-        &Token::Punct(Punct::Colon) => {
-            if let Some(&Token::Punct(Punct::QuestionMark)) = top_token(stack) {
-                out.write_new(" ");
-                added_space = true;
+    /// Close the braceless bodies that end with a statement that just ended at
+    /// the current stack depth. `next` is the next token.
+    fn end_statement(&mut self, next: Option<&Tok<'a>>) {
+        while self.bodies.last().map(|v| v.depth) == Some(self.stack.len()) {
+            let body = self.bodies.pop().unwrap();
+            let continues = match body.clause {
+                Clause::If => matches!(
+                    next.map(|v| &v.token),
+                    Some(&Token::Keyword(Keyword::Else(_)))
+                ),
+                Clause::Do => true,
+                _ => false,
+            };
+            self.close_body(&body, next, continues);
+            if continues {
+                break;
             }
         }
-        &Token::Keyword(Keyword::Else(_)) => match last_token.as_ref().map(|v| &v.token) {
-            Some(&Token::Punct(Punct::CloseBrace)) | Some(&Token::Punct(Punct::Period)) => (),
-            Some(_) => {
-                out.write_new(" ");
-                added_space = true;
-            }
-            None => (),
-        },
-        _ => (),
     }
 
-    if is_asi(token, last_token) || decrements_indent(token, stack) {
-        if !added_newline {
-            out.write_new("\n");
-            added_newline = true;
+    /// Close `body`. `continues` is set if its statement continues after it,
+    /// e.g. with `else`.
+    fn close_body(&mut self, body: &Body, next: Option<&Tok<'a>>, continues: bool) {
+        self.indent_level = max(1, self.indent_level) - 1;
+        if body.brace.is_none() {
+            // Whatever follows the body goes on a new line.
+            if !self.added_newline {
+                self.out.write_new("\n");
+                self.added_newline = true;
+            }
+            return;
         }
+        if !self.added_newline {
+            self.out.write_new("\n");
+        }
+        self.out.write_indent(self.indent_level);
+        self.out.write_new("}");
+        self.added_newline = false;
+        self.added_space = false;
+        // Lay out what follows as if the `}` was there all along, and on the
+        // same line as the next token.
+        let pos = match next {
+            Some(t) => {
+                // E.g. `if (a) {return}` then `(x)` on the next line.
+                if !continues {
+                    self.out.write_new("\n");
+                    self.added_newline = true;
+                }
+                t.start
+            }
+            None => {
+                self.out.write_new("\n");
+                self.added_newline = true;
+                self.last_token
+                    .as_ref()
+                    .map(|v| v.end)
+                    .unwrap_or(self.out.current)
+            }
+        };
+        self.last_token = Some(synthetic_close_brace(pos));
     }
 
-    if added_newline {
-        match &token.token {
-            &Token::Keyword(Keyword::Case(_)) | &Token::Keyword(Keyword::Default(_)) => {
-                out.write_indent(max(1, indent_level) - 1);
+    /// If `token` is a `&&`, `||`, `??`, `?` or `:` operator of an expression in
+    /// statement position, returns the stack depth of the statement.
+    fn short_circuit_depth(&self, token: &Tok) -> Option<usize> {
+        if is_inline(&self.stack) {
+            return None;
+        }
+        let depth = match &token.token {
+            &Token::Punct(Punct::DoubleAmpersand) | &Token::Punct(Punct::DoublePipe) => {
+                self.stack.len()
             }
-            _ => {
-                out.write_indent(indent_level);
+            &Token::Punct(Punct::QuestionMark) if token.typescript.is_none() => self.stack.len(),
+            &Token::Punct(Punct::Colon)
+                if top_token(&self.stack) == Some(&Token::Punct(Punct::QuestionMark)) =>
+            {
+                self.stack.len() - 1
             }
+            _ => return None,
+        };
+        if in_statement(&self.stack[..depth]) {
+            Some(depth)
+        } else {
+            None
         }
-    } else if !added_space && need_space_after(token, last_token) {
-        out.write_new(" ");
     }
-}
 
-fn belongs_on_stack(token: &Tok) -> bool {
-    match &token.token {
-        &Token::Keyword(Keyword::Case(_))
-        | &Token::Keyword(Keyword::Default(_))
-        | &Token::Keyword(Keyword::Do(_))
-        | &Token::Keyword(Keyword::Switch(_))
-        | &Token::Punct(Punct::OpenBrace)
-        | &Token::Punct(Punct::OpenParen)
-        | &Token::Punct(Punct::OpenBracket)
-        | &Token::Punct(Punct::QuestionMark) => true,
-        _ => false,
+    /// True if the output so far ends with a complete top-level statement
+    /// followed by a newline, so that formatting can restart from scratch.
+    pub(crate) fn at_top_level(&self) -> bool {
+        self.stack.is_empty()
+            && self.indent_level == 0
+            && self.added_newline
+            && self.class_depth.is_none()
+            && self.chains.is_empty()
+            && self.sequences.is_empty()
+            && self.short_circuits.is_empty()
+            && self.bodies.is_empty()
+            && !self.declaration
     }
-}
 
-fn should_pop_stack(token: &Tok, stack: &Stack) -> bool {
-    match &token.token {
-        &Token::Keyword(Keyword::While(_)) => match top_token(stack) {
-            Some(&Token::Keyword(Keyword::Do(_))) => true,
-            _ => false,
-        },
-        &Token::Punct(Punct::CloseBracket)
-        | &Token::Punct(Punct::CloseParen)
-        | &Token::Punct(Punct::CloseBrace) => true,
-        &Token::Punct(Punct::Colon) => match top_token(stack) {
-            Some(&Token::Keyword(Keyword::Case(_)))
-            | Some(&Token::Keyword(Keyword::Default(_)))
-            | Some(&Token::Punct(Punct::QuestionMark)) => true,
-            _ => false,
-        },
-        _ => false,
+    pub(crate) fn finish(mut self) -> (String, Vec<SourceMapping>) {
+        while let Some(body) = self.bodies.pop() {
+            self.close_body(&body, None, false);
+        }
+        if self.options.insert_semicolons && self.out.buffer.len() == self.last_token_end {
+            if let Some(ref t) = self.last_token {
+                if can_end_statement(t, &self.stack) {
+                    self.out.write_new(";");
+                }
+            }
+        }
+        if !self.added_newline {
+            self.out.write_new("\n");
+        }
+        (self.out.buffer, self.out.mappings)
     }
 }
 
-fn add_comment(token: &Tok, next_token: Option<&Tok>, indent_level: u32, out: &mut Writer) -> bool {
-    out.write_indent(indent_level);
-    let comment = if let &Token::Comment(ref c) = &token.token {
-        c
-    } else {
-        panic!("Must be a comment");
-    };
-    let need_new_line = match comment.kind {
-        CommentKind::Multi | CommentKind::Html => {
-            out.write_new("/*");
-            let mut first = true;
-            for line in comment.content.lines() {
-                if first {
-                    first = false;
-                } else {
-                    out.write_indent(indent_level);
-                    out.write_new("  ");
+/// A position in the source, counted the way RESS counts: `line` from 0 and
+/// `column` from 1.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Cursor {
+    pub(crate) idx: usize,
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+}
+
+/// RESS's `Scanner`, except that a `/` after a postfix `++` or `--` is a
+/// division. RESS takes it for the start of a regular expression, so that e.g.
+/// `a++/2` fails to tokenize and `a--/2;b=/c/` has a regular expression
+/// `/2;b=/c`.
+///
+/// In JSX mode, the lexer also scans JSX elements, which RESS knows nothing
+/// about, handing the expressions in their braces back to RESS.
+pub(crate) struct Lexer<'a> {
+    pub(crate) source: &'a str,
+    pub(crate) scanner: Scanner<'a>,
+    /// The end line of the last token that is not a comment, if it ends an
+    /// operand.
+    pub(crate) operand_line: Option<usize>,
+    /// The end of the last token that is not a comment, if it is a postfix
+    /// `++` or `--`.
+    pub(crate) postfix_end: Option<usize>,
+    /// Whether to recognize JSX elements.
+    pub(crate) jsx: bool,
+    /// The JSX contexts we are in, innermost last.
+    pub(crate) jsx_stack: Vec<JsxContext>,
+    /// Which part of a JSX element the last token is.
+    pub(crate) jsx_role: Option<Jsx>,
+    /// Set when a `<` after the last token that is not a comment is a
+    /// less-than operator.
+    pub(crate) less_than: bool,
+    /// Set when the last token that is not a comment is `.`, so that a keyword
+    /// after it is a property name.
+    pub(crate) after_period: bool,
+}
+
+impl<'a> Lexer<'a> {
+    pub(crate) fn new(source: &'a str) -> Lexer<'a> {
+        Lexer {
+            source,
+            scanner: Scanner::new(source),
+            operand_line: None,
+            postfix_end: None,
+            jsx: false,
+            jsx_stack: Vec::new(),
+            jsx_role: None,
+            less_than: false,
+            after_period: false,
+        }
+    }
+
+    /// Where RESS will scan the next token.
+    pub(crate) fn cursor(&self) -> Cursor {
+        let state = self.scanner.get_state().manual_state;
+        Cursor {
+            idx: state.cursor,
+            line: state.new_line_count,
+            column: state.line_cursor,
+        }
+    }
+
+    /// Move `cursor` over `len` bytes of the source.
+    pub(crate) fn advance(&self, cursor: &mut Cursor, len: usize) {
+        let bytes = self.source.as_bytes();
+        for i in cursor.idx..cursor.idx + len {
+            match bytes[i] {
+                b'\r' if bytes.get(i + 1) == Some(&b'\n') => cursor.column += 1,
+                b'\n' | b'\r' => {
+                    cursor.line += 1;
+                    cursor.column = 1;
                 }
-                out.write_new(line);
+                _ => cursor.column += 1,
             }
-            out.write_indent(indent_level);
-            out.write_new("*/");
-            next_token
-                .map(|t| t.start.line != token.start.line)
-                .unwrap_or(false)
         }
-        CommentKind::Hashbang | CommentKind::Single => {
-            out.write_new(&comment.to_string());
-            true
+        cursor.idx += len;
+    }
+
+    /// Continue RESS's scan from `cursor`, after any whitespace.
+    pub(crate) fn resume(&mut self, mut cursor: Cursor) {
+        let bytes = self.source.as_bytes();
+        while bytes
+            .get(cursor.idx)
+            .map(|b| b.is_ascii_whitespace())
+            .unwrap_or(false)
+        {
+            self.advance(&mut cursor, 1);
         }
-    };
-    if need_new_line {
-        out.write_new("\n");
-    } else {
-        out.write_new(" ");
+        let mut state = self.scanner.get_state();
+        state.manual_state.cursor = cursor.idx;
+        state.manual_state.new_line_count = cursor.line;
+        state.manual_state.line_cursor = cursor.column;
+        self.scanner.set_state(state);
     }
-    need_new_line
 }
 
-fn convert_position(pos: Position) -> SourceCoord {
-    SourceCoord {
-        line: SourceMapLine(pos.line as u32 - 1),
-        column: SourceMapColumn(pos.column as u32 - 1),
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Item<&'a str>, ress::error::Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.starts_jsx() {
+            return Some(Ok(self.open_jsx_element()));
+        }
+        if self.in_jsx_element() {
+            if let Some(item) = self.next_jsx() {
+                return Some(item);
+            }
+            // Malformed, so make what we can of the rest as JS.
+            self.jsx_stack.clear();
+        }
+        self.jsx_role = None;
+        if let Some(end) = self.postfix_end {
+            let rest = self.source[end..].trim_start();
+            if rest.starts_with('/') && !rest.starts_with("//") && !rest.starts_with("/*") {
+                // RESS decides from the tokens before a `/`, so pretend that
+                // the last one was an identifier.
+                let mut state = self.scanner.get_state();
+                state
+                    .last_three
+                    .push((&Token::Ident(Ident::from("")), 0).into());
+                self.scanner.set_state(state);
+            }
+        }
+        let item = self.scanner.next()?;
+        if let Ok(ref item) = item {
+            if !item.token.is_comment() {
+                self.postfix_end = match &item.token {
+                    &Token::Punct(Punct::DoublePlus) | &Token::Punct(Punct::DoubleDash)
+                        if self.operand_line == Some(item.location.start.line) =>
+                    {
+                        Some(item.span.end)
+                    }
+                    _ => None,
+                };
+                self.operand_line = if is_operand_end(&item.token) {
+                    Some(item.location.end.line)
+                } else {
+                    None
+                };
+                // A postfix operator or a property name, e.g. `a.if`, ends an
+                // operand too.
+                self.less_than = precedes_less_than(&item.token)
+                    || self.postfix_end.is_some()
+                    || (self.after_period && item.token.is_keyword());
+                self.after_period = item.token.matches_punct(Punct::Period);
+            }
+            self.track_jsx_expression(&item.token);
+        }
+        Some(item)
     }
 }
 
-fn convert_token<'a>(item: Item<&'a str>) -> Tok<'a> {
-    debug!("token: {:?} -> {:?}", item.location.start, item.token);
-    Tok {
-        token: item.token,
-        start: convert_position(item.location.start),
-        end: convert_position(item.location.end),
-        starts_array_literal: false,
-    }
+pub(crate) fn scan(source: &str, jsx: bool) -> impl Iterator<Item = Tok<'_>> {
+    let mut lexer = Lexer::new(source);
+    lexer.jsx = jsx;
+    std::iter::from_fn(move || loop {
+        if let Ok(v) = lexer.next()? {
+            let postfix = lexer.postfix_end == Some(v.span.end);
+            let mut token = convert_token(v);
+            token.jsx = lexer.jsx_role;
+            token.postfix = postfix;
+            return Some(token);
+        }
+    })
 }
 
 /// Prettyprint JS source code. Returns the prettyprinted code,
@@ -631,100 +3191,71 @@ fn convert_token<'a>(item: Item<&'a str>) -> Tok<'a> {
 /// assert_eq!(pretty, "function x(a) {\n  return a;\n}\n");
 /// ```
 pub fn prettyprint(source: &str) -> (String, Vec<SourceMapping>) {
-    let mut indent_level = 0;
-    let mut out = Writer::new(2);
-    let mut added_newline = false;
-    let mut added_space = false;
-    let mut stack: Stack = Vec::new();
-    let mut scanner = Scanner::new(source)
-        .filter_map(|v| match v {
-            Ok(v) => Some(convert_token(v)),
-            Err(_) => None,
-        })
-        .peekable();
-    let mut last_token: Option<Tok> = None;
-
-    while let Some(mut token) = scanner.next() {
-        let next_token = scanner.peek();
-        match &token.token {
-            &Token::Comment(_) => {
-                let comment_indent_level = if last_token
-                    .as_ref()
-                    .map(|v| v.end.line == token.start.line)
-                    .unwrap_or(false)
-                {
-                    out.write_new(" ");
-                    0
-                } else {
-                    indent_level
-                };
-                added_newline = add_comment(&token, next_token, comment_indent_level, &mut out);
-                added_space = !added_newline;
-                continue;
-            }
-            &Token::EoF => break,
-            _ => (),
-        }
-
-        token.starts_array_literal = starts_array_literal(&token, &last_token);
-
-        if belongs_on_stack(&token) {
-            stack.push(token.clone());
-        }
-
-        if decrements_indent(&token, &stack) {
-            indent_level = max(1, indent_level) - 1;
-            if let &Token::Punct(Punct::CloseBrace) = &token.token {
-                if stack.len() >= 2 {
-                    if let &Token::Keyword(Keyword::Switch(_)) = &stack[stack.len() - 2].token {
-                        indent_level = max(1, indent_level) - 1;
-                    }
-                }
-            }
-        }
-
-        prepend_white_space(
-            &token,
-            &last_token,
-            &stack,
-            added_newline,
-            added_space,
-            indent_level,
-            &mut out,
-        );
-
-        out.write(&token.token.to_string(), token.start);
-
-        added_space = false;
-        let mut same_line_comment = false;
-        if let Some(&Token::Comment(_)) = next_token.as_ref().map(|v| &v.token) {
-            if next_token.unwrap().start.line == token.end.line {
-                same_line_comment = true;
-            }
-        }
-        if !same_line_comment {
-            added_newline = append_newline(&token, &stack, &mut out);
-        }
-
-        if should_pop_stack(&token, &stack) {
-            stack.pop();
-            if let &Token::Punct(Punct::CloseBrace) = &token.token {
-                if let Some(&Token::Keyword(Keyword::Switch(_))) = stack.last().map(|v| &v.token) {
-                    stack.pop();
-                }
-            }
-        }
+    prettyprint_with_options(source, &PrettyprintOptions::default())
+}
 
-        if increments_indent(&token) {
-            indent_level += 1;
+/// Like `prettyprint`, but with control over how the prettyprinting is done.
+///
+/// Example:
+/// ```
+/// let options = prettify_js::PrettyprintOptions {
+///     threads: 4,
+///     ..Default::default()
+/// };
+/// let (pretty, _) = prettify_js::prettyprint_with_options("function x(a){return a;}", &options);
+/// assert_eq!(pretty, "function x(a) {\n  return a;\n}\n");
+/// ```
+pub fn prettyprint_with_options(
+    source: &str,
+    options: &PrettyprintOptions,
+) -> (String, Vec<SourceMapping>) {
+    if options.threads > 1 {
+        if let Some(result) = parallel::prettyprint_parallel(source, options) {
+            return result;
         }
-
-        last_token = Some(token);
     }
+    let mut formatter = Formatter::new(options);
+    formatter.format(scan(source, options.jsx));
+    formatter.finish()
+}
 
-    if !added_newline {
-        out.write_new("\n");
+/// Like `prettyprint_with_options`, but gives up with an error once `budget`
+/// is exhausted. Prettyprinting runs on a single thread when the budget limits
+/// anything other than the input size.
+///
+/// Example:
+/// ```
+/// use prettify_js::*;
+/// let budget = Budget {
+///     max_tokens: Some(3),
+///     ..Default::default()
+/// };
+/// let err = try_prettyprint("function x(a){return a;}", &PrettyprintOptions::default(), &budget)
+///     .unwrap_err();
+/// assert_eq!(err.limit, BudgetLimit::Tokens);
+/// assert_eq!(err.tokens, 3);
+/// ```
+pub fn try_prettyprint(
+    source: &str,
+    options: &PrettyprintOptions,
+    budget: &Budget,
+) -> Result<(String, Vec<SourceMapping>), BudgetExceeded> {
+    if budget
+        .max_input_size
+        .map(|v| source.len() > v)
+        .unwrap_or(false)
+    {
+        return Err(BudgetExceeded {
+            limit: BudgetLimit::InputSize,
+            tokens: 0,
+            output_size: 0,
+            position: None,
+        });
     }
-
-    (out.buffer, out.mappings)
+    if !budget.is_limited() {
+        return Ok(prettyprint_with_options(source, options));
+    }
+    let mut formatter = Formatter::new(options);
+    formatter.format_with_budget(scan(source, options.jsx), budget)?;
+    Ok(formatter.finish())
 }
@@ -0,0 +1,184 @@
+/// Prettyprinting of a selected region of a source file, leaving the rest of the
+/// file untouched.
+use std::collections::{BTreeMap, HashSet};
+
+use super::*;
+use crate::prettyprint::{convert_token, Formatter, Lexer};
+
+use ress::*;
+
+/// The new text, plus the coordinate at its end.
+/// Line breaks are counted the same way RESS counts them in the original source,
+/// so a `\r\n` counts as two.
+pub(crate) struct Output {
+    pub(crate) text: String,
+    pub(crate) mappings: Vec<SourceMapping>,
+    pub(crate) coord: SourceCoord,
+}
+
+impl Output {
+    pub(crate) fn new(capacity: usize) -> Output {
+        Output {
+            text: String::with_capacity(capacity),
+            mappings: Vec::new(),
+            coord: SourceCoord {
+                line: SourceMapLine(0),
+                column: SourceMapColumn(0),
+            },
+        }
+    }
+    pub(crate) fn push(&mut self, s: &str) {
+        self.text.push_str(s);
+        self.advance(s);
+    }
+    /// Move the coordinate past `s` without adding it to the text.
+    pub(crate) fn advance(&mut self, s: &str) {
+        for ch in s.chars() {
+            match ch {
+                '\n' | '\r' | '\u{2028}' | '\u{2029}' => {
+                    self.coord.line.0 += 1;
+                    self.coord.column.0 = 0;
+                }
+                _ => self.coord.column.0 += ch.len_utf16() as u32,
+            }
+        }
+    }
+    /// Copy `source` up to the start of each of `tokens`, mapping each token to
+    /// its new position. Returns the offset copied up to.
+    fn copy_tokens(&mut self, source: &str, mut copied: usize, tokens: &[(Span, Tok)]) -> usize {
+        for (span, token) in tokens {
+            self.push(&source[copied..span.start]);
+            self.mappings.push(SourceMapping {
+                from: self.coord,
+                to: token.start,
+            });
+            copied = span.start;
+        }
+        copied
+    }
+}
+
+/// Returns the whitespace indenting the line containing byte offset `offset`.
+pub(crate) fn line_indent(source: &str, offset: usize) -> &str {
+    let line_start = source[..offset]
+        .rfind(['\n', '\r', '\u{2028}', '\u{2029}'])
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    let line = &source[line_start..offset];
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+/// Returns the lines of prettyprinted output that start inside one of `tokens`,
+/// e.g. in the middle of a multi-line template literal, given the `mappings` of
+/// the output to the source of `tokens`. Indenting those lines would change the
+/// token. Comments don't count, since they are laid out on a single line.
+pub(crate) fn lines_inside_tokens<'a, I>(tokens: I, mappings: &[SourceMapping]) -> HashSet<u32>
+where
+    I: IntoIterator<Item = &'a Tok<'a>>,
+{
+    // Mappings are in output order, so the first mapping of a token's start is
+    // where the token was written.
+    let mut starts = BTreeMap::new();
+    for m in mappings {
+        starts.entry(m.to).or_insert(m.from.line.0);
+    }
+    let mut lines = HashSet::new();
+    for token in tokens {
+        if token.end.line <= token.start.line || token.token.is_comment() {
+            continue;
+        }
+        if let Some(&line) = starts.get(&token.start) {
+            lines.extend(line + 1..=line + token.end.line.0 - token.start.line.0);
+        }
+    }
+    lines
+}
+
+/// Prettyprint only the tokens of `source` that start at or after `start` and
+/// before `end`, leaving the text outside that region unchanged.
+/// Lines of the prettyprinted region are indented to match the line the region
+/// starts on. `start` and `end` count lines the way RESS does, like the
+/// mappings, so a `\r\n` counts as two line breaks.
+///
+/// Returns the full new text plus SourceMappings for the whole file, in the same
+/// form as `prettyprint`. Tokens outside the region map one-to-one to their
+/// (possibly shifted) positions in the new text.
+///
+/// Example:
+/// ```
+/// use prettify_js::*;
+/// let source = "if (a) {\n  f=function(){return 1};\n}\n";
+/// let coord = |line, column| SourceCoord {
+///     line: SourceMapLine(line),
+///     column: SourceMapColumn(column),
+/// };
+/// let (pretty, _) = prettyprint_range(source, coord(1, 4), coord(1, 24));
+/// assert_eq!(pretty, "if (a) {\n  f=function () {\n    return 1\n  };\n}\n");
+/// ```
+pub fn prettyprint_range(
+    source: &str,
+    start: SourceCoord,
+    end: SourceCoord,
+) -> (String, Vec<SourceMapping>) {
+    let mut lexer = Lexer::new(source);
+    let mut tokens = Vec::new();
+    while let Some(Ok(v)) = lexer.next() {
+        if v.is_eof() {
+            continue;
+        }
+        let span = v.span;
+        let mut token = convert_token(v);
+        token.postfix = lexer.postfix_end == Some(span.end);
+        tokens.push((span, token));
+    }
+    let in_range = |t: &Tok| t.start >= start && t.start < end;
+    let first = tokens.iter().position(|(_, t)| in_range(t));
+    let last = tokens.iter().rposition(|(_, t)| in_range(t));
+
+    let mut out = Output::new(source.len());
+    let (first, last) = match (first, last) {
+        (Some(first), Some(last)) => (first, last),
+        _ => {
+            let copied = out.copy_tokens(source, 0, &tokens);
+            out.push(&source[copied..]);
+            return (out.text, out.mappings);
+        }
+    };
+
+    let copied = out.copy_tokens(source, 0, &tokens[..first]);
+    let region_start = tokens[first].0.start;
+    out.push(&source[copied..region_start]);
+
+    let mut formatter = Formatter::new(&PrettyprintOptions::default());
+    formatter.out.last_from = tokens[first].1.start;
+    formatter.format(tokens[first..=last].iter().map(|(_, t)| t.clone()));
+    let (region, region_mappings) = formatter.finish();
+    let region = region.strip_suffix('\n').unwrap_or(&region);
+    let indent = line_indent(source, region_start);
+    let inside_tokens = lines_inside_tokens(
+        tokens[first..=last].iter().map(|(_, t)| t),
+        &region_mappings,
+    );
+    for mut m in region_mappings {
+        if m.from.line.0 == 0 {
+            m.from.column.0 += out.coord.column.0;
+        } else if !inside_tokens.contains(&m.from.line.0) {
+            m.from.column.0 += indent.len() as u32;
+        }
+        m.from.line.0 += out.coord.line.0;
+        out.mappings.push(m);
+    }
+    for (i, line) in region.split('\n').enumerate() {
+        if i > 0 {
+            out.push("\n");
+            if !line.is_empty() && !inside_tokens.contains(&(i as u32)) {
+                out.push(indent);
+            }
+        }
+        out.push(line);
+    }
+
+    let copied = out.copy_tokens(source, tokens[last].0.end, &tokens[last + 1..]);
+    out.push(&source[copied..]);
+    (out.text, out.mappings)
+}
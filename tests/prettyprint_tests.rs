@@ -74,3 +74,717 @@ fn print() {
         "AAAA;AACA,SAAS,CAAC,CAAC,CAAC,EAAE,EACZ,OAAO,CAAC,EACV"
     );
 }
+
+#[test]
+fn parallel() {
+    let mut source = String::new();
+    for i in 0..200 {
+        source.push_str(&format!(
+            "var v{0}=function(a){{if(a){{return [a,{0}]}}else{{return `t${{a}}`/2}}}};\n x{0}(/re;/g,'é;');",
+            i
+        ));
+    }
+    let options = PrettyprintOptions {
+        threads: 4,
+        min_chunk_size: 1000,
+        ..Default::default()
+    };
+    assert_eq!(
+        prettyprint_with_options(&source, &options),
+        prettyprint(&source)
+    );
+
+    // RESS counts `\r\n` as two line breaks.
+    let source = source.replace('\n', "\r\n\r\n");
+    let options = PrettyprintOptions {
+        preserve_blank_lines: 1,
+        ..options
+    };
+    let sequential = PrettyprintOptions {
+        threads: 1,
+        ..options.clone()
+    };
+    assert_eq!(
+        prettyprint_with_options(&source, &options),
+        prettyprint_with_options(&source, &sequential)
+    );
+    let source = "a();\r\n\r\nb();\r\nc();\r\nd();\r\n";
+    let options = PrettyprintOptions {
+        min_chunk_size: 4,
+        ..options
+    };
+    let sequential = PrettyprintOptions {
+        threads: 1,
+        ..options.clone()
+    };
+    assert_eq!(
+        prettyprint_with_options(source, &options),
+        prettyprint_with_options(source, &sequential)
+    );
+}
+
+#[test]
+fn range() {
+    let start = m(0, 6, 0, 0).from;
+    let end = m(0, 25, 0, 0).from;
+    let (pretty, mappings) = prettyprint_range("a();b(function(){x();y()});c();\nd();", start, end);
+    assert_eq!(pretty, "a();b(function () {\n  x();\n  y()\n});c();\nd();");
+    assert_eq!(
+        &mappings[..6],
+        &[
+            m(0, 0, 0, 0),
+            m(0, 1, 0, 1),
+            m(0, 2, 0, 2),
+            m(0, 3, 0, 3),
+            m(0, 4, 0, 4),
+            m(0, 5, 0, 5)
+        ]
+    );
+    assert_eq!(
+        mappings
+            .iter()
+            .filter(|v| v.from.line.0 >= 2)
+            .cloned()
+            .collect::<Vec<_>>(),
+        vec![
+            m(2, 2, 0, 21),
+            m(2, 3, 0, 22),
+            m(2, 4, 0, 23),
+            m(3, 0, 0, 24),
+            m(3, 1, 0, 25),
+            m(3, 2, 0, 26),
+            m(3, 3, 0, 27),
+            m(3, 4, 0, 28),
+            m(3, 5, 0, 29),
+            m(3, 6, 0, 30),
+            m(4, 0, 1, 0),
+            m(4, 1, 1, 1),
+            m(4, 2, 1, 2),
+            m(4, 3, 1, 3),
+        ]
+    );
+
+    // Lines inside a template literal are not reindented.
+    let source = "if(a){\n  x=`a\nb${c}`;y()\n}";
+    let start = m(1, 2, 0, 0).from;
+    let end = m(3, 0, 0, 0).from;
+    let (pretty, mappings) = prettyprint_range(source, start, end);
+    assert_eq!(pretty, "if(a){\n  x = `a\nb${c}`;\n  y()\n}");
+    // Nor are the mappings on them, e.g. of `c`.
+    assert!(mappings.iter().any(|v| v.from == m(2, 3, 0, 0).from));
+
+    // Block comments are laid out on one line, so the lines after them are reindented.
+    let source = "if(a){\n  /* a\n b */x()\n}";
+    let (pretty, _) = prettyprint_range(source, m(1, 2, 0, 0).from, m(3, 0, 0, 0).from);
+    assert_eq!(pretty, "if(a){\n  /* a   b */\n  x()\n}");
+
+    // Like RESS, coordinates count `\r\n` as two line breaks.
+    let source = "a();\r\nb(function(){x()});\r\nc();";
+    let start = m(2, 0, 0, 0).from;
+    let end = m(2, 99, 0, 0).from;
+    let (pretty, mappings) = prettyprint_range(source, start, end);
+    assert_eq!(pretty, "a();\r\nb(function () {\n  x()\n});\r\nc();");
+    assert!(mappings.contains(&m(2, 0, 2, 0)));
+    assert!(mappings.contains(&m(3, 2, 2, 13)));
+    assert!(mappings.contains(&m(6, 0, 4, 0)));
+}
+
+#[test]
+fn budget() {
+    let source = "function x(a){return a;}";
+    let options = PrettyprintOptions::default();
+    let budget = Budget {
+        max_input_size: Some(10),
+        ..Default::default()
+    };
+    let err = try_prettyprint(source, &options, &budget).unwrap_err();
+    assert_eq!(err.limit, BudgetLimit::InputSize);
+    assert_eq!(err.position, None);
+
+    let budget = Budget {
+        max_output_size: Some(12),
+        ..Default::default()
+    };
+    let err = try_prettyprint(source, &options, &budget).unwrap_err();
+    assert_eq!(err.limit, BudgetLimit::OutputSize);
+    assert_eq!((err.tokens, err.output_size), (5, 13));
+    assert_eq!(err.position, Some(m(0, 13, 0, 0).from));
+
+    let cancelled = || true;
+    let budget = Budget {
+        cancelled: Some(&cancelled),
+        ..Default::default()
+    };
+    let err = try_prettyprint(source, &options, &budget).unwrap_err();
+    assert_eq!(err.limit, BudgetLimit::Cancelled);
+    assert_eq!(err.tokens, 0);
+
+    let budget = Budget {
+        max_tokens: Some(100),
+        ..Default::default()
+    };
+    assert_eq!(
+        try_prettyprint(source, &options, &budget).unwrap(),
+        prettyprint(source)
+    );
+}
+
+#[test]
+fn modern_operators() {
+    let (pretty, mappings) = prettyprint("a??b;x?.[0];a||=b;a&&=c;a??=d");
+    assert_eq!(pretty, "a ?? b;\nx?.[0];\na ||= b;\na &&= c;\na ??= d\n");
+    assert_eq!(
+        mappings
+            .iter()
+            .filter(|v| v.from.line.0 == 1)
+            .cloned()
+            .collect::<Vec<_>>(),
+        vec![
+            m(1, 0, 0, 5),
+            m(1, 1, 0, 6),
+            m(1, 3, 0, 8),
+            m(1, 4, 0, 9),
+            m(1, 5, 0, 10),
+            m(1, 6, 0, 11)
+        ]
+    );
+    let (pretty, _) = prettyprint("y=a?.b?c:d;z=a?b?.c:d;q=c?.5:1");
+    assert_eq!(
+        pretty,
+        "y = a?.b ? c : d;\nz = a ? b?.c : d;\nq = c ? .5 : 1\n"
+    );
+    let (pretty, _) = prettyprint("g(a=>a*2,(a,b)=>{return a})");
+    assert_eq!(pretty, "g(a => a * 2, (a, b) => {\n  return a\n})\n");
+}
+
+#[test]
+fn class_body() {
+    let (pretty, _) = prettyprint(
+        "class A extends B{constructor(a){super(a)}get x(){return 1}static #y=2;static{init()}['k'](){}}",
+    );
+    assert_eq!(
+        pretty,
+        "class A extends B {\n  constructor(a) {\n    super(a)\n  }\n\n  get x() {\n    return 1\n  }\n\n  static #y = 2;\n\n  static {\n    init()\n  }\n\n  ['k']() {}\n}\n"
+    );
+    let (pretty, _) = prettyprint("class A{a(){}\n// b\nb(){}};x={class:1}");
+    assert_eq!(
+        pretty,
+        "class A {\n  a() {}\n\n  // b\n  b() {}\n};\nx = {\n  class: 1\n}\n"
+    );
+}
+
+#[test]
+fn object_literals() {
+    let (pretty, _) = prettyprint("const {a,b}=x");
+    assert_eq!(pretty, "const {\n  a,\n  b\n} = x\n");
+    let options = PrettyprintOptions {
+        inline_object_width: Some(40),
+        ..Default::default()
+    };
+    let (pretty, _) = prettyprint_with_options(
+        "f({a:1});const {a,b}=x;q=a?{x:1}:{y:2};o={[k]:[1]};if(a){b()}",
+        &options,
+    );
+    assert_eq!(
+        pretty,
+        "f({a: 1});\nconst {a, b} = x;\nq = a ? {x: 1} : {y: 2};\no = {[k]: [1]};\nif (a) {\n  b()\n}\n"
+    );
+}
+
+#[test]
+fn packed_arrays() {
+    let (pretty, _) = prettyprint("f([1,2])");
+    assert_eq!(pretty, "f([\n  1,\n  2\n])\n");
+    let options = PrettyprintOptions {
+        pack_array_width: Some(12),
+        ..Default::default()
+    };
+    let (pretty, mappings) =
+        prettyprint_with_options("a=[1,2,3];b=[10,11,12,13,14,15];c=[x]", &options);
+    assert_eq!(
+        pretty,
+        "a = [1, 2, 3];\nb = [\n  10, 11, 12,\n  13, 14, 15\n];\nc = [\n  x\n]\n"
+    );
+    // Every element is still mapped.
+    assert!(mappings.contains(&m(3, 2, 0, 22)));
+}
+
+#[test]
+fn callbacks() {
+    let (pretty, _) = prettyprint(
+        "a.map(e=>{return e+1}).filter(e=>e.x);p.then(function(){}).catch(e=>{throw e});x=y?()=>{z()}:()=>1;(function(){})()",
+    );
+    assert_eq!(
+        pretty,
+        "a.map(e => {\n  return e + 1\n}).filter(e => e.x);\np.then(function () {}).catch(e => {\n  throw e\n});\nx = y ? () => {\n  z()\n} : () => 1;\n(function () {})()\n"
+    );
+}
+
+#[test]
+fn method_chains() {
+    let source = "$(x).addClass(a).css(b).on(c,d);$(x).a().b();f(a().b().c().d(),2)";
+    let (pretty, _) = prettyprint(source);
+    assert_eq!(
+        pretty,
+        "$(x).addClass(a).css(b).on(c, d);\n$(x).a().b();\nf(a().b().c().d(), 2)\n"
+    );
+    let options = PrettyprintOptions {
+        method_chain_width: Some(16),
+        ..Default::default()
+    };
+    let (pretty, _) = prettyprint_with_options(source, &options);
+    assert_eq!(
+        pretty,
+        "$(x)\n  .addClass(a)\n  .css(b)\n  .on(c, d);\n$(x).a().b();\nf(a()\n  .b()\n  .c()\n  .d(), 2)\n"
+    );
+}
+
+#[test]
+fn sequences() {
+    let source = "var a=1,b;a(),b=c;for(i=0,j=1;;)f(a,b)";
+    let (pretty, _) = prettyprint(source);
+    assert_eq!(
+        pretty,
+        "var a = 1,\nb;\na(),\nb = c;\nfor (i = 0, j = 1; ; ) f(a, b)\n"
+    );
+    let options = PrettyprintOptions {
+        split_sequences: true,
+        ..Default::default()
+    };
+    let (pretty, _) = prettyprint_with_options(source, &options);
+    assert_eq!(
+        pretty,
+        "var a = 1,\n  b;\na(),\n  b = c;\nfor (i = 0, j = 1; ; ) f(a, b)\n"
+    );
+}
+
+#[test]
+fn short_circuits() {
+    let source = "a&&b(1,2,3)||c(4,5,6);x&&y();q=a?b(1,2,3):c(4,5,6);if(a&&b(1,2,3,4,5,6))z()";
+    let options = PrettyprintOptions {
+        short_circuit_width: Some(16),
+        ..Default::default()
+    };
+    let (pretty, _) = prettyprint_with_options(source, &options);
+    assert_eq!(
+        pretty,
+        "a\n  && b(1, 2, 3)\n  || c(4, 5, 6);\nx && y();\nq = a\n  ? b(1, 2, 3)\n  : c(4, 5, 6);\nif (a && b(1, 2, 3, 4, 5, 6)) z()\n"
+    );
+}
+
+#[test]
+fn insert_semicolons() {
+    let options = PrettyprintOptions {
+        insert_semicolons: true,
+        ..Default::default()
+    };
+    let (pretty, mappings) = prettyprint_with_options(
+        "function f(){a=b\n(c)\nreturn\nx}\nx={a:1}\nif(a)\nb()\ny",
+        &options,
+    );
+    assert_eq!(
+        pretty,
+        "function f() {\n  a = b(c);\n  return;\n  x;\n}\nx = {\n  a: 1\n};\nif (a)\nb();\ny;\n"
+    );
+    // Inserted semicolons are not mapped.
+    assert!(!mappings.iter().any(|m| m.from
+        == SourceCoord {
+            line: SourceMapLine(2),
+            column: SourceMapColumn(8)
+        }));
+
+    // ASI ends a statement after a postfix operator or `return` at the end of a
+    // line, whatever follows.
+    let (pretty, _) = prettyprint_with_options(
+        "if(a)x++\n(function(){})()\nfunction g(){return\n(x)}",
+        &options,
+    );
+    assert_eq!(
+        pretty,
+        "if (a) x++;\n(function () {})();\nfunction g() {\n  return;\n  (x);\n}\n"
+    );
+
+    // Statements can end with the body of a function, class or arrow function
+    // expression, but declarations need no `;`.
+    let (pretty, _) = prettyprint_with_options(
+        "x=function(){}\ny=class{}\nz=b=>{}\nfunction f(){}\nclass A{}\nexport default class{}\nw();",
+        &options,
+    );
+    assert_eq!(
+        pretty,
+        "x = function () {};\ny = class {};\nz = b => {};\nfunction f() {}\nclass A {}\nexport default class {}\nw();\n"
+    );
+    // ASI always ends `do ... while (...)`.
+    let (pretty, _) = prettyprint_with_options("do x();while(y)z()", &options);
+    assert_eq!(pretty, "do x();\nwhile (y);\nz();\n");
+}
+
+#[test]
+fn braceless_bodies() {
+    let source = "if(a)b();else c();for(;;)x++";
+    let options = PrettyprintOptions {
+        braceless_bodies: BracelessBodies::Indent,
+        ..Default::default()
+    };
+    let (pretty, _) = prettyprint_with_options(source, &options);
+    assert_eq!(pretty, "if (a)\n  b();\nelse\n  c();\nfor (; ; )\n  x++\n");
+    let options = PrettyprintOptions {
+        braceless_bodies: BracelessBodies::Braces,
+        ..Default::default()
+    };
+    let (pretty, _) = prettyprint_with_options(source, &options);
+    assert_eq!(
+        pretty,
+        "if (a) {\n  b();\n} else {\n  c();\n}\nfor (; ; ) {\n  x++\n}\n"
+    );
+
+    // A line break ends `return` whatever follows, and braces go before
+    // comments.
+    let (pretty, _) = prettyprint_with_options("if(a)return\n(x)\nif(b)/*c*/d()", &options);
+    assert_eq!(
+        pretty,
+        "if (a) {\n  return\n}\n(x)\nif (b) {\n  /*c*/ d()\n}\n"
+    );
+    // Where the body ends is unclear, so it is laid out without braces.
+    let source = "if(a)b()\n{c()}\nd()";
+    let (pretty, mappings) = prettyprint_with_options(source, &options);
+    let indented = prettyprint_with_options(
+        source,
+        &PrettyprintOptions {
+            braceless_bodies: BracelessBodies::Indent,
+            ..Default::default()
+        },
+    );
+    assert_eq!(pretty, "if (a)\n  b()\n  {\n    c()\n  }\nd()\n");
+    assert_eq!((pretty, mappings), indented);
+}
+
+#[test]
+fn insert_blank_lines() {
+    let source = "\"use strict\";var a=1;function f(){}\n// g\nclass G{}x(),\
+f([function(e){\"use strict\";e()},function(e){}]);";
+    let options = PrettyprintOptions {
+        insert_blank_lines: true,
+        ..Default::default()
+    };
+    let (pretty, _) = prettyprint_with_options(source, &options);
+    assert_eq!(
+        pretty,
+        "\"use strict\";\n\nvar a = 1;\n\nfunction f() {}\n\n// g\nclass G {}\n\nx(),\nf([\n  function (e) {\n    \"use strict\";\n\n    e()\n  },\n\n  function (e) {}\n]);\n"
+    );
+}
+
+#[test]
+fn preserve_blank_lines() {
+    let source = "a();\n\n\n\n// b\nb();\nfunction f(){x()\n\ny()}\n\nvar o={a:1,\n\nb:2}";
+    let options = PrettyprintOptions {
+        preserve_blank_lines: 2,
+        ..Default::default()
+    };
+    let (pretty, mappings) = prettyprint_with_options(source, &options);
+    assert_eq!(
+        pretty,
+        "a();\n\n\n// b\nb();\nfunction f() {\n  x()\n\n  y()\n}\n\nvar o = {\n  a: 1,\n  b: 2\n}\n"
+    );
+    assert!(mappings.contains(&m(4, 0, 5, 0)));
+}
+
+#[test]
+fn switch_case() {
+    let (pretty, _) = prettyprint(
+        "switch(a){case 1:switch(b){case x?y:z:f();default:g()}break;case(p?1:2):{h()}\
+default:{i()}j()}o={default:1,case:2}",
+    );
+    assert_eq!(
+        pretty,
+        "switch (a) {\n  case 1:\n    switch (b) {\n      case x ? y : z:\n        f();\n      default:\n        g()\n    }\n    break;\n  case (p ? 1 : 2): {\n    h()\n  }\n  default: {\n    i()\n  }\n    j()\n}\no = {\n  default: 1,\n  case: 2\n}\n"
+    );
+}
+
+#[test]
+fn labels_and_loops() {
+    let (pretty, _) = prettyprint(
+        "a:for(var i=0,j={k:1,l:[1,2]};i<j.k;i++,j.l.pop()){for(const [m,n] of [[1,2]])continue a}\
+do{x()}while(y)z();if(b){}\nelse{}",
+    );
+    assert_eq!(
+        pretty,
+        "a:\nfor (var i = 0, j = {k: 1, l: [1, 2]}; i < j.k; i++, j.l.pop()) {\n  for (const [m, n] of [[1, 2]]) continue a\n}\ndo {\n  x()\n} while (y)\nz();\nif (b) {} else {}\n"
+    );
+
+    // Only an identifier at the start of a statement can be a label.
+    let (pretty, _) = prettyprint("{\"s\":\"x\",\"t\":\"a/b\"}");
+    assert_eq!(pretty, "{\n  \"s\": \"x\",\n  \"t\": \"a/b\"\n}\n");
+    let (pretty, _) = prettyprint("x=`${{a:1}.a}`");
+    assert_eq!(pretty, "x = `${{\n  a: 1\n}.a}`\n");
+}
+
+#[test]
+fn es_modules() {
+    let (pretty, _) = prettyprint(
+        "import{a as b,c}from\"x\";import d,{e}from\"y\";import*as f from\"z\";\
+import{aaaaaaaaaaaa,bbbbbbbbbbbb,cccccccccccc,dddddddddddd,eeeeeeeeeeee}from\"w\";\
+export{g as default};export*from\"v\";export default{h:1};import(\"u\").then(i);j=import.meta.url",
+    );
+    assert_eq!(
+        pretty,
+        "import {a as b, c} from \"x\";\nimport d, {e} from \"y\";\nimport * as f from \"z\";\nimport {\n  aaaaaaaaaaaa,\n  bbbbbbbbbbbb,\n  cccccccccccc,\n  dddddddddddd,\n  eeeeeeeeeeee\n} from \"w\";\nexport {g as default};\nexport * from \"v\";\nexport default {\n  h: 1\n};\nimport(\"u\").then(i);\nj = import.meta.url\n"
+    );
+}
+
+#[test]
+fn specifier_list_width() {
+    let options = PrettyprintOptions {
+        specifier_list_width: None,
+        ..Default::default()
+    };
+    let (pretty, _) = prettyprint_with_options("import{a as b,c}from\"x\";export{d}", &options);
+    assert_eq!(
+        pretty,
+        "import {\n  a as b,\n  c\n} from \"x\";\nexport {\n  d\n}\n"
+    );
+}
+
+#[test]
+fn regex_and_division() {
+    // Each source is on one line, with the regular expressions it contains.
+    let cases: &[(&str, &str, &[&str])] = &[
+        ("a=b/c/d", "a = b / c / d\n", &[]),
+        ("a=(b)/2/c[0]/d", "a = (b) / 2 / c[0] / d\n", &[]),
+        ("a=b++/2", "a = b++ / 2\n", &[]),
+        ("a=b--/2;x=/y/", "a = b-- / 2;\nx = /y/\n", &["/y/"]),
+        (
+            "if(a)/x/.test(s)&&f()",
+            "if (a) /x/.test(s) && f()\n",
+            &["/x/"],
+        ),
+        (
+            "if(a){}/[/]\\//g.test(s)",
+            "if (a) {}\n/[/]\\//g.test(s)\n",
+            &["/[/]\\//g"],
+        ),
+        (
+            "function f(){return/x/g}",
+            "function f() {\n  return /x/g\n}\n",
+            &["/x/g"],
+        ),
+        (
+            "x=a?/b/:typeof/c/",
+            "x = a ? /b/ : typeof /c/\n",
+            &["/b/", "/c/"],
+        ),
+        ("e%=a/=b", "e %= a /= b\n", &[]),
+    ];
+    for &(source, expected, regexes) in cases {
+        let (pretty, mappings) = prettyprint(source);
+        assert_eq!(pretty, expected, "{}", source);
+        assert_eq!(prettyprint(&pretty).0, pretty, "{}", source);
+        for regex in regexes {
+            let start = source.find(regex).unwrap() as u32;
+            let end = start + regex.len() as u32;
+            assert!(
+                mappings.iter().any(|m| m.to.column.0 == start),
+                "{}",
+                source
+            );
+            assert!(
+                !mappings
+                    .iter()
+                    .any(|m| m.to.column.0 > start && m.to.column.0 < end),
+                "{}",
+                source
+            );
+        }
+    }
+}
+
+#[test]
+fn three_character_operators() {
+    let (pretty, _) = prettyprint("if(e===null||e>>>0===1)e>>>=a!==b");
+    assert_eq!(pretty, "if (e === null || e >>> 0 === 1) e >>>= a !== b\n");
+}
+
+#[test]
+fn async_and_generators() {
+    let cases: &[(&str, &str)] = &[
+        ("function*g(){yield*x}", "function* g() {\n  yield* x\n}\n"),
+        (
+            "x=function*(){yield;f(yield,1)}",
+            "x = function* () {\n  yield;\n  f(yield, 1)\n}\n",
+        ),
+        ("async function*g(){}", "async function* g() {}\n"),
+        ("f=async x=>x", "f = async x => x\n"),
+        ("f=async(a,b)=>a", "f = async (a, b) => a\n"),
+        ("f=async()=>{}", "f = async () => {}\n"),
+        ("async(a,b)", "async(a, b)\n"),
+        ("await(x)", "await (x)\n"),
+        ("for await(const a of b){}", "for await (const a of b) {}\n"),
+        (
+            "o={async*m(){},*n(){},p:a*b}",
+            "o = {\n  async *m() {},\n  *n() {},\n  p: a * b\n}\n",
+        ),
+        (
+            "class C{static async*m(){}*n(){}}",
+            "class C {\n  static async *m() {}\n\n  *n() {}\n}\n",
+        ),
+    ];
+    for &(source, expected) in cases {
+        let (pretty, _) = prettyprint(source);
+        assert_eq!(pretty, expected, "{}", source);
+        assert_eq!(prettyprint(&pretty).0, pretty, "{}", source);
+    }
+}
+
+#[test]
+fn jsx() {
+    let options = PrettyprintOptions {
+        jsx: true,
+        ..Default::default()
+    };
+    let source = "x=<div className=\"a\">{items.map(i=><li key={i}>{i}</li>)}<p>Hi, {name}!</p>\
+<br/></div>;y=a<b>c;z=<b style={{color:\"red\"}}>\n  two\n  lines\n</b>";
+    let (pretty, mappings) = prettyprint_with_options(source, &options);
+    assert_eq!(
+        pretty,
+        "x = <div className=\"a\">\n  {items.map(i => <li key={i}>{i}</li>)}\n  <p>Hi, {name}!</p>\n  <br />\n</div>;\ny = a < b > c;\nz = <b style={{color: \"red\"}}>two lines</b>\n"
+    );
+    let column = |s| source.find(s).unwrap() as u32;
+    assert!(mappings.contains(&m(2, 2, 0, column("<p>"))));
+    assert!(mappings.contains(&m(2, 5, 0, column("Hi,"))));
+    assert!(mappings.contains(&m(4, 0, 0, column("</div>"))));
+    assert!(mappings.contains(&m(6, 30, 1, 2)));
+    assert!(mappings.contains(&m(6, 34, 2, 2)));
+
+    // A `<` after a postfix operator or a keyword property name compares.
+    let (pretty, _) = prettyprint_with_options("for(i=0;i--<n>>1;)f(a,\"}\")", &options);
+    assert_eq!(pretty, "for (i = 0; i-- < n >> 1; ) f(a, \"}\")\n");
+    let (pretty, _) = prettyprint_with_options("c=x++<y>z;d=1", &options);
+    assert_eq!(pretty, "c = x++ < y > z;\nd = 1\n");
+    let (pretty, _) = prettyprint_with_options("c=a.if<y>z;d=1", &options);
+    assert_eq!(pretty, "c = a.if < y > z;\nd = 1\n");
+
+    let (pretty, _) = prettyprint_with_options("x=(\n<div>{f(a)}</div>\n)\ny()", &options);
+    assert_eq!(pretty, "x = (<div>{f(a)}</div>)\ny()\n");
+
+    // A line break would remove the whitespace between children on one line.
+    let (pretty, _) = prettyprint_with_options("x=<p>Hi <b>there</b>!</p>", &options);
+    assert_eq!(pretty, "x = <p>Hi <b>there</b>!</p>\n");
+    let (pretty, _) = prettyprint_with_options("x=<div><b>a</b> <i>b</i></div>", &options);
+    assert_eq!(pretty, "x = <div><b>a</b> <i>b</i></div>\n");
+}
+
+#[test]
+fn typescript() {
+    let options = PrettyprintOptions {
+        typescript: true,
+        ..Default::default()
+    };
+    let source = "let a:number=1;function f<T>(x:T,y?:string):T{return x}\
+interface P{a:number;b?:string[]}let m:Map<string,Array<number>>=new Map<string,Array<number>>();\
+const c=[1]as const;if(a<b&&c>d)x=a<0?b:c>=d";
+    let (pretty, mappings) = prettyprint_with_options(source, &options);
+    assert_eq!(
+        pretty,
+        "let a: number = 1;\nfunction f<T>(x: T, y?: string): T {\n  return x\n}\ninterface P {\n  a: number;\n  b?: string[]\n}\nlet m: Map<string, Array<number>> = new Map<string, Array<number>>();\nconst c = [\n  1\n] as const;\nif (a < b && c > d) x = a < 0 ? b : c >= d\n"
+    );
+    // The `=` of `>>=` is mapped on its own.
+    let column = source.find(">>=").unwrap() as u32 + 2;
+    assert!(mappings.contains(&m(8, 34, 0, column)));
+
+    let (pretty, _) =
+        prettyprint_with_options("declare module \"m\"{export const a:number}", &options);
+    assert_eq!(
+        pretty,
+        "declare module \"m\" {\n  export const a: number\n}\n"
+    );
+}
+
+#[test]
+fn keyword_property_names() {
+    let (pretty, _) = prettyprint("x=a.default;y=[a.in,a.class];f(a.new);g(a.this,b)");
+    assert_eq!(
+        pretty,
+        "x = a.default;\ny = [\n  a.in,\n  a.class\n];\nf(a.new);\ng(a.this, b)\n"
+    );
+}
+
+#[test]
+fn json() {
+    let source = "{\"a\":[1,-2,\"x\"],\"b\":{},\"c\":{\"d\":null,\"e\":[true,[3,4,5,6,7,8,9]]}}";
+    let (pretty, mappings) = prettyprint_json(source, None);
+    assert_eq!(
+        pretty,
+        "{\n  \"a\": [\n    1,\n    -2,\n    \"x\"\n  ],\n  \"b\": {},\n  \"c\": {\n    \"d\": null,\n    \"e\": [\n      true,\n      [\n        3,\n        4,\n        5,\n        6,\n        7,\n        8,\n        9\n      ]\n    ]\n  }\n}\n"
+    );
+    let column = |s| source.find(s).unwrap() as u32;
+    assert!(mappings.contains(&m(8, 4, 0, column("\"d\""))));
+    assert!(mappings.contains(&m(8, 9, 0, column("null"))));
+    let (pretty, _) = prettyprint_json(source, Some(12));
+    assert_eq!(
+        pretty,
+        "{\n  \"a\": [1, -2, \"x\"],\n  \"b\": {},\n  \"c\": {\n    \"d\": null,\n    \"e\": [\n      true,\n      [\n        3, 4, 5, 6,\n        7, 8, 9\n      ]\n    ]\n  }\n}\n"
+    );
+    // A sign is not followed by a space.
+    let (pretty, _) = prettyprint_json("[-10,-20,-30,-40,-50,-60,-70,-80,-90]", Some(14));
+    assert_eq!(
+        pretty,
+        "[\n  -10, -20, -30,\n  -40, -50, -60,\n  -70, -80, -90\n]\n"
+    );
+}
+
+#[test]
+fn html_inline_scripts() {
+    let source = "<html>\n  <script type=\"text/template\"><b>{x}</b></script>\n  <SCRIPT type='module' data-a=\"x>y\">if(a){b()}</SCRIPT><p>\n<!-- <script>c()</script> --></p>\n</html>";
+    let (pretty, mappings) = prettyprint_html(source, &PrettyprintOptions::default());
+    assert_eq!(
+        pretty,
+        "<html>\n  <script type=\"text/template\"><b>{x}</b></script>\n  <SCRIPT type='module' data-a=\"x>y\">\n    if (a) {\n      b()\n    }\n  </SCRIPT><p>\n<!-- <script>c()</script> --></p>\n</html>"
+    );
+    let line = source.lines().nth(2).unwrap();
+    let column = |s| line.find(s).unwrap() as u32;
+    assert!(mappings.contains(&m(3, 4, 2, column("if"))));
+    assert!(mappings.contains(&m(4, 6, 2, column("b()"))));
+    assert!(mappings.contains(&m(6, 2, 2, column("</SCRIPT>"))));
+    assert!(mappings.contains(&m(7, 0, 3, 0)));
+
+    // Lines inside a template literal are not indented.
+    let (pretty, _) = prettyprint_html(
+        "<div>\n  <script>x=`a\nb`</script>",
+        &PrettyprintOptions::default(),
+    );
+    assert_eq!(pretty, "<div>\n  <script>\n    x = `a\nb`\n  </script>");
+    let (pretty, _) = prettyprint_html(
+        "<div>\n  <script>/* a\n b */x()</script>",
+        &PrettyprintOptions::default(),
+    );
+    assert_eq!(
+        pretty,
+        "<div>\n  <script>\n    /* a   b */\n    x()\n  </script>"
+    );
+
+    // Like RESS, mappings count `\r\n` as two line breaks.
+    let (pretty, mappings) = prettyprint_html(
+        "<p>\r\n<script>\r\nif(a){b()}\r\n</script>\r\n<i>",
+        &PrettyprintOptions::default(),
+    );
+    assert_eq!(
+        pretty,
+        "<p>\r\n<script>\n  if (a) {\n    b()\n  }\n</script>\r\n<i>"
+    );
+    assert!(mappings.contains(&m(3, 2, 4, 0)));
+    assert!(mappings.contains(&m(6, 0, 6, 0)));
+    assert!(mappings.contains(&m(8, 0, 8, 0)));
+}
+
+#[test]
+fn css() {
+    let source = "/* a */@media screen{a:hover,b::before{content:\"x;}\";background:url(data:a;b) 0 0}i{}}p{margin:0 auto;color:red/* b */}";
+    let (pretty, mappings) = prettyprint_css(source);
+    assert_eq!(
+        pretty,
+        "/* a */\n@media screen {\n  a:hover, b::before {\n    content: \"x;}\";\n    background: url(data:a;b) 0 0\n  }\n  i {}\n}\np {\n  margin: 0 auto;\n  color: red /* b */\n}\n"
+    );
+    let column = |s| source.find(s).unwrap() as u32;
+    assert!(mappings.contains(&m(4, 16, 0, column("url("))));
+    assert!(mappings.contains(&m(9, 10, 0, column("0 auto"))));
+}